@@ -0,0 +1,107 @@
+use crate::level_info::{LevelScheme, Multipolarity};
+/// This module computes the γ-γ angular correlations of a cascade, which make
+/// two of its gamma rays more or less likely to reach the same detector than
+/// if they were emitted independently.
+use rgsl::coupling_coefficients::{_3j, _6j};
+
+/// Solid angle attenuation coefficients Q2 and Q4 of the detector, which are
+/// one for a point detector and smaller for a close one.
+#[derive(Debug, Clone, Copy)]
+pub struct Attenuation {
+    pub q2: f64,
+    pub q4: f64,
+}
+
+fn twice(j: f64) -> i32 {
+    (2.0 * j).round() as i32
+}
+
+/// F_k(L L' J1 J) of a gamma ray of multipolarities L and L' between the
+/// levels with spins J1 and J.
+pub fn f_coefficient(k: u32, l: u32, lp: u32, j1: f64, j: f64) -> f64 {
+    let (k, l, lp) = (k as i32, l as i32, lp as i32);
+    let phase = if (twice(j1 + j) / 2 - 1).rem_euclid(2) == 0 {
+        1.0
+    } else {
+        -1.0
+    };
+    let norm = f64::sqrt(((2 * k + 1) * (2 * l + 1) * (2 * lp + 1)) as f64 * (2.0 * j + 1.0));
+    phase
+        * norm
+        * _3j(2 * l, 2 * lp, 2 * k, 2, -2, 0)
+        * _6j(2 * l, 2 * lp, 2 * k, twice(j), twice(j), twice(j1))
+}
+
+/// A_k of one gamma ray of a cascade through the intermediate level with spin
+/// `middle`, where `outer` is the spin of the level at its other end. `first`
+/// for the gamma ray that feeds the intermediate level, whose mixing ratio
+/// enters with the opposite sign.
+pub fn a_coefficient(k: u32, m: &Multipolarity, outer: f64, middle: f64, first: bool) -> f64 {
+    let (l, d) = (m.order, m.mixing);
+    let sign = if first { -1.0 } else { 1.0 };
+    (f_coefficient(k, l, l, outer, middle)
+        + sign * 2.0 * d * f_coefficient(k, l, l + 1, outer, middle)
+        + d * d * f_coefficient(k, l + 1, l + 1, outer, middle))
+        / (1.0 + d * d)
+}
+
+/// The angular correlation weight W_jki of each pair of consecutive gamma rays
+/// j -> k -> i that reach the same detector, 1 + A22 Q2² + A44 Q4². Both gamma
+/// rays are attenuated by the detector, so each Q_k enters squared.
+#[derive(Debug, Clone)]
+pub struct Correlation {
+    n: usize,
+    weights: Vec<f64>,
+}
+
+impl Correlation {
+    /// `None` if no pair of consecutive gamma rays has the spins of its three
+    /// levels and both multipolarities.
+    pub fn new(scheme: &LevelScheme, attenuation: Attenuation) -> Option<Self> {
+        let n = scheme.levels.len();
+        let mut weights = vec![1.0; n * n * n];
+        let mut any = false;
+        for first in scheme.branches.iter() {
+            for second in scheme.branches.iter().filter(|b| b.from == first.to) {
+                let spins = (
+                    scheme.levels[first.from].spin,
+                    scheme.levels[first.to].spin,
+                    scheme.levels[second.to].spin,
+                );
+                let (Some(m1), Some(m2), (Some(j1), Some(j), Some(j2))) =
+                    (&first.multipolarity, &second.multipolarity, spins)
+                else {
+                    continue;
+                };
+                let a = |k| a_coefficient(k, m1, j1, j, true) * a_coefficient(k, m2, j2, j, false);
+                let w = 1.0 + a(2) * attenuation.q2.powi(2) + a(4) * attenuation.q4.powi(2);
+                weights[(first.from * n + first.to) * n + second.to] = w;
+                any = true;
+            }
+        }
+        any.then_some(Self { n, weights })
+    }
+
+    pub fn weight(&self, j: usize, k: usize, i: usize) -> f64 {
+        self.weights[(j * self.n + k) * self.n + i]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn co60_cascade() {
+        // 4 -> 2 -> 0 with two pure E2 gamma rays.
+        let e2 = Multipolarity {
+            order: 2,
+            mixing: 0.0,
+            dmixing: 0.0,
+        };
+        let a22 = a_coefficient(2, &e2, 4.0, 2.0, true) * a_coefficient(2, &e2, 0.0, 2.0, false);
+        let a44 = a_coefficient(4, &e2, 4.0, 2.0, true) * a_coefficient(4, &e2, 0.0, 2.0, false);
+        assert!((a22 - 5.0 / 49.0).abs() < 1e-12, "A22 = {a22}");
+        assert!((a44 - 4.0 / 441.0).abs() < 1e-12, "A44 = {a44}");
+    }
+}
@@ -0,0 +1,163 @@
+use crate::efficiency::{EfficiencyModel, Interpolation, TotalEfficiency, read_efficiency};
+use crate::error::{LineParser, ParseError, ParseErrorKind};
+/// This module describes an array of detectors, e.g. the crystals of a clover
+/// or several HPGe detectors around one source. Each detector gives its own
+/// spectrum, and the detectors of an add-back group give one more, in which
+/// the photons that reach several of its members add up.
+use std::fs;
+use std::path::Path;
+
+/// One detector with its peak and total efficiency.
+#[derive(Debug, Clone)]
+pub struct Detector {
+    pub name: String,
+    pub peak: EfficiencyModel,
+    pub total: EfficiencyModel,
+}
+
+/// Detectors whose signals are added back into one spectrum. A photon that
+/// deposits energy in any of them counts once, so the total efficiency of the
+/// group is the sum of those of its members. `peak` is the measured add-back
+/// peak efficiency, the sum of the member peak efficiencies when it is `None`,
+/// which misses the events that add-back recovers from scattering between the
+/// crystals.
+#[derive(Debug, Clone)]
+pub struct AddBackGroup {
+    pub name: String,
+    pub members: Vec<usize>,
+    pub peak: Option<EfficiencyModel>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DetectorArray {
+    pub detectors: Vec<Detector>,
+    pub groups: Vec<AddBackGroup>,
+}
+
+impl DetectorArray {
+    /// Name, peak and total efficiency of each spectrum: one per detector,
+    /// then one per add-back group.
+    pub fn spectra(&self) -> Vec<(String, EfficiencyModel, TotalEfficiency)> {
+        let singles = self
+            .detectors
+            .iter()
+            .map(|d| (d.name.clone(), d.peak.clone(), d.total.clone().into()));
+        let groups = self.groups.iter().map(|g| {
+            let members = || g.members.iter().map(|&m| &self.detectors[m]);
+            let sum = |models: Vec<EfficiencyModel>| EfficiencyModel::Sum {
+                models,
+                scale_unc: 0.0,
+            };
+            let peak = g
+                .peak
+                .clone()
+                .unwrap_or_else(|| sum(members().map(|d| d.peak.clone()).collect()));
+            let total = sum(members().map(|d| d.total.clone()).collect());
+            (g.name.clone(), peak, total.into())
+        });
+        singles.chain(groups).collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ArraySection {
+    None,
+    Detectors,
+    AddBack,
+}
+
+impl ArraySection {
+    fn name(&self) -> &'static str {
+        match self {
+            ArraySection::None => "",
+            ArraySection::Detectors => "Detectors",
+            ArraySection::AddBack => "Add-Back",
+        }
+    }
+}
+
+/// Read an array file. The Detectors section has lines
+/// `name peak_file total_file`, and the optional Add-Back section lines
+/// `name peak_file member...`, where a peak file `-` stands for the sum of
+/// the member peak efficiencies. Sections end with an empty line, lines
+/// starting with `#` are comments and relative paths are relative to the
+/// array file.
+pub fn read_array(
+    file_path: &str,
+    peak_interp: Option<Interpolation>,
+    total_interp: Option<Interpolation>,
+) -> Result<DetectorArray, ParseError> {
+    let file_content = fs::read_to_string(file_path).map_err(|e| ParseError::read(file_path, e))?;
+    let dir = Path::new(file_path).parent().unwrap_or(Path::new(""));
+    let resolve = |name: String| dir.join(name).to_string_lossy().into_owned();
+
+    let mut array = DetectorArray::default();
+    let mut section = ArraySection::None;
+    for (i, line) in file_content.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            section = ArraySection::None;
+            continue;
+        }
+        if trimmed.starts_with('#') {
+            continue;
+        }
+        let mut p = LineParser::new(file_path, i + 1, section.name(), trimmed);
+        match section {
+            ArraySection::None => {
+                section = match trimmed {
+                    "Detectors" => ArraySection::Detectors,
+                    "Add-Back" => ArraySection::AddBack,
+                    _ => return Err(p.error(trimmed, ParseErrorKind::UnknownSection)),
+                }
+            }
+            ArraySection::Detectors => {
+                let name: String = p.next("detector name")?;
+                let peak_file = resolve(p.next("peak efficiency file")?);
+                let total_file = resolve(p.next("total efficiency file")?);
+                array.detectors.push(Detector {
+                    name,
+                    peak: read_efficiency(&peak_file, peak_interp)?,
+                    total: read_efficiency(&total_file, total_interp)?,
+                });
+            }
+            ArraySection::AddBack => {
+                let name: String = p.next("add-back group name")?;
+                let peak_file: String = p.next("peak efficiency file")?;
+                let peak = match peak_file.as_str() {
+                    "-" => None,
+                    _ => Some(read_efficiency(&resolve(peak_file), peak_interp)?),
+                };
+                let mut members = Vec::new();
+                while let Some(member) = p.optional::<String>("add-back member")? {
+                    let idx = array
+                        .detectors
+                        .iter()
+                        .position(|d| d.name == member)
+                        .ok_or_else(|| {
+                            p.error(trimmed, ParseErrorKind::UnknownDetector(member.clone()))
+                        })?;
+                    members.push(idx);
+                }
+                if members.is_empty() {
+                    return Err(p.error(trimmed, ParseErrorKind::Missing("add-back member")));
+                }
+                array.groups.push(AddBackGroup {
+                    name,
+                    members,
+                    peak,
+                });
+            }
+        }
+    }
+    if array.detectors.is_empty() {
+        return Err(ParseError::new(
+            file_path,
+            0,
+            "Detectors",
+            "",
+            ParseErrorKind::NoDetectors,
+        ));
+    }
+    Ok(array)
+}
@@ -0,0 +1,477 @@
+use crate::{
+    angular::Attenuation,
+    efficiency::{EfficiencyEvaluator, OutOfRange, TotalEfficiency},
+    error::EfficiencyRangeError,
+    level_info::{Decay, LevelScheme, Observation},
+    monte_carlo::UndefinedTransition,
+    parametric::{ParametricEfficiency, ParametricForm},
+    sum_correction::SchemeMatrices,
+};
+/// This module fits a summing free peak efficiency to calibration sources,
+/// alternating between the fit and the summing corrections it implies.
+use rgsl::{MatrixF64, MultifitLinearWorkspace, VectorF64};
+use std::fmt;
+
+/// A calibration source: its level scheme, the measured peak areas and the
+/// decay, which has to give the number of decays during the measurement.
+#[derive(Debug, Clone)]
+pub struct CalibrationSource {
+    pub name: String,
+    pub scheme: LevelScheme,
+    pub observations: Vec<Observation>,
+    pub decay: Decay,
+}
+
+/// Settings for the calibration fit.
+#[derive(Debug, Clone)]
+pub struct CalibrationOptions {
+    /// Degree of the polynomial in ln(E/E0) fitted to ln ε.
+    pub degree: usize,
+    /// Reference energy E0 in keV.
+    pub e0: f64,
+    pub max_iterations: usize,
+    /// The iteration stops once no correction changes by more than this
+    /// relative amount.
+    pub tolerance: f64,
+    /// Total efficiency of a 511 keV annihilation photon in place of the
+    /// total efficiency curve.
+    pub annihilation_eff: Option<f64>,
+    /// Coincidence resolving time in seconds for levels with a half-life.
+    pub coincidence_window: Option<f64>,
+    /// Solid angle attenuation for γ-γ angular correlations.
+    pub attenuation: Option<Attenuation>,
+}
+
+impl Default for CalibrationOptions {
+    fn default() -> Self {
+        Self {
+            degree: 3,
+            e0: 1000.0,
+            max_iterations: 50,
+            tolerance: 1e-6,
+            annihilation_eff: None,
+            coincidence_window: None,
+            attenuation: None,
+        }
+    }
+}
+
+/// A measured efficiency, one for each observation of each source.
+#[derive(Debug, Clone)]
+pub struct CalibrationPoint {
+    /// Index of the source in the list given to `calibrate`.
+    pub source: usize,
+    pub from: usize,
+    pub to: usize,
+    pub energy: f64,
+    /// Emission probability per decay.
+    pub emission: f64,
+    /// Summing correction applied to the peak area.
+    pub correction: f64,
+    pub efficiency: f64,
+    pub defficiency: f64,
+}
+
+/// The fitted peak efficiency and the points it was fitted to.
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    /// A Debertin model whose range is that of the points.
+    pub model: ParametricEfficiency,
+    pub points: Vec<CalibrationPoint>,
+    pub chi2: f64,
+    pub ndf: usize,
+    pub iterations: usize,
+}
+
+#[derive(Debug)]
+pub enum CalibrationError {
+    /// An observation is not a transition of its source.
+    UndefinedTransition(String, UndefinedTransition),
+    /// The decay of a source does not give the number of decays.
+    NoDecays(String),
+    /// An observation has no uncertainty to weigh it with.
+    NoUncertainty(String, usize, usize),
+    /// There have to be more points than parameters.
+    TooFewPoints(usize, usize),
+    /// The total efficiency does not cover a transition.
+    Range(String, EfficiencyRangeError),
+    /// The least squares fit failed.
+    Fit,
+    /// The corrections still changed after `max_iterations`, with the last fit.
+    NotConverged(Box<Calibration>),
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalibrationError::UndefinedTransition(source, e) => write!(f, "{e} of {source}"),
+            CalibrationError::NoDecays(source) => write!(
+                f,
+                "the Decay section of {source} gives neither the decays nor the activity and live time"
+            ),
+            CalibrationError::NoUncertainty(source, from, to) => write!(
+                f,
+                "observation {from} -> {to} of {source} has no uncertainty"
+            ),
+            CalibrationError::TooFewPoints(points, params) => write!(
+                f,
+                "{points} calibration points are too few to fit {params} parameters"
+            ),
+            CalibrationError::Range(source, e) => write!(f, "{e} in {source}"),
+            CalibrationError::Fit => write!(f, "the efficiency fit failed"),
+            CalibrationError::NotConverged(c) => write!(
+                f,
+                "the corrections did not converge in {} iterations",
+                c.iterations
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CalibrationError {}
+
+/// Weighted least squares fit of ln ε = Σ a_k x^k, x = ln(E/E0), to the
+/// points. The covariance is scaled up by the reduced χ² if that is above one.
+fn fit(
+    points: &[CalibrationPoint],
+    options: &CalibrationOptions,
+) -> Result<(ParametricEfficiency, f64), CalibrationError> {
+    let n = points.len();
+    let p = options.degree + 1;
+    let mut x = MatrixF64::new(n, p).expect("Failed to allocate design matrix");
+    let mut w = VectorF64::new(n).unwrap();
+    let mut y = VectorF64::new(n).unwrap();
+    for (i, point) in points.iter().enumerate() {
+        let xi = (point.energy / options.e0).ln();
+        for k in 0..p {
+            x.set(i, k, xi.powi(k as i32));
+        }
+        y.set(i, point.efficiency.ln());
+        w.set(i, (point.efficiency / point.defficiency).powi(2));
+    }
+
+    let mut params = VectorF64::new(p).unwrap();
+    let mut cov = MatrixF64::new(p, p).unwrap();
+    let mut work = MultifitLinearWorkspace::new(n, p).ok_or(CalibrationError::Fit)?;
+    let chi2 = work
+        .wlinear(&x, &w, &y, &mut params, &mut cov)
+        .map_err(|_| CalibrationError::Fit)?;
+    let scale = (chi2 / (n - p) as f64).max(1.0);
+
+    let low = points
+        .iter()
+        .map(|p| p.energy)
+        .fold(f64::INFINITY, f64::min);
+    let high = points.iter().map(|p| p.energy).fold(0.0, f64::max);
+    let covariance = (0..p * p).map(|k| scale * cov.get(k / p, k % p)).collect();
+    let mut model = ParametricEfficiency::new(
+        ParametricForm::Debertin,
+        options.e0,
+        params.as_slice().unwrap().to_vec(),
+        covariance,
+    )
+    .ok_or(CalibrationError::Fit)?;
+    model.range = Some((low, high));
+    Ok((model, chi2))
+}
+
+/// Fit a summing free peak efficiency to the observations of the calibration
+/// sources. Starting without corrections, each iteration fits the efficiency
+/// to the corrected points and recomputes the corrections of every source with
+/// it and `total`, until they change by less than `options.tolerance`. The
+/// emission probabilities are those of the observations if given, and follow
+/// from the level scheme and the decay branching otherwise. Running out of
+/// `options.max_iterations` is an error that still carries the last fit.
+pub fn calibrate(
+    sources: &[CalibrationSource],
+    total: &TotalEfficiency,
+    options: &CalibrationOptions,
+) -> Result<Calibration, CalibrationError> {
+    let matrices: Vec<SchemeMatrices> = sources
+        .iter()
+        .map(|s| SchemeMatrices::new(&s.scheme, options.coincidence_window, options.attenuation))
+        .collect();
+
+    let mut points = Vec::new();
+    for (k, (source, m)) in sources.iter().zip(matrices.iter()).enumerate() {
+        let emission = m.emission();
+        let Some((decays, ddecays)) = source.decay.n_decays() else {
+            return Err(CalibrationError::NoDecays(source.name.clone()));
+        };
+        let (branching, dbranching) = source.decay.branching.unwrap_or((1.0, 0.0));
+        for o in source.observations.iter() {
+            if !source.scheme.has_transition(o.from, o.to) {
+                return Err(CalibrationError::UndefinedTransition(
+                    source.name.clone(),
+                    UndefinedTransition {
+                        from: o.from,
+                        to: o.to,
+                    },
+                ));
+            }
+            let (i_gamma, di_gamma) = o.emission.unwrap_or((
+                branching * emission.get(o.from, o.to),
+                dbranching * emission.get(o.from, o.to),
+            ));
+            let efficiency = o.counts / (decays * i_gamma);
+            let rel = f64::sqrt(
+                (o.dcounts / o.counts).powi(2)
+                    + (ddecays / decays).powi(2)
+                    + (di_gamma / i_gamma).powi(2),
+            );
+            if rel == 0.0 {
+                return Err(CalibrationError::NoUncertainty(
+                    source.name.clone(),
+                    o.from,
+                    o.to,
+                ));
+            }
+            points.push(CalibrationPoint {
+                source: k,
+                from: o.from,
+                to: o.to,
+                energy: m.energies.get(o.from, o.to),
+                emission: i_gamma,
+                correction: 1.0,
+                efficiency,
+                defficiency: rel * efficiency,
+            });
+        }
+    }
+    let n_params = options.degree + 1;
+    if points.len() <= n_params {
+        return Err(CalibrationError::TooFewPoints(points.len(), n_params));
+    }
+
+    let mut total_eff = total.evaluator();
+    total_eff.annihilation = options.annihilation_eff;
+    let mut iterations = 0;
+    let mut converged = false;
+    loop {
+        let (model, chi2) = fit(&points, options)?;
+        if converged || iterations == options.max_iterations {
+            let calibration = Calibration {
+                model,
+                ndf: points.len() - n_params,
+                points,
+                chi2,
+                iterations,
+            };
+            return match converged {
+                true => Ok(calibration),
+                false => Err(CalibrationError::NotConverged(Box::new(calibration))),
+            };
+        }
+        iterations += 1;
+
+        // The fit is only constrained between the points, but every transition
+        // of the sources needs an efficiency.
+        let mut function = model.function();
+        function.out_of_range = OutOfRange::Extrapolate;
+        let mut peak_eff = EfficiencyEvaluator::Function(function);
+        let corrections = sources
+            .iter()
+            .zip(matrices.iter())
+            .map(|(source, m)| {
+                m.correction(&mut peak_eff, &mut total_eff)
+                    .map_err(|e| CalibrationError::Range(source.name.clone(), e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut change: f64 = 0.0;
+        for point in points.iter_mut() {
+            let correction = corrections[point.source].get(point.from, point.to);
+            change = change.max((correction / point.correction - 1.0).abs());
+            let scale = correction / point.correction;
+            point.efficiency *= scale;
+            point.defficiency *= scale;
+            point.correction = correction;
+        }
+        converged = change < options.tolerance;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::efficiency::{EfficiencyCurve, Interpolation};
+    use crate::level_info::{Branch, Level};
+
+    /// ln ε = -3 - 0.8 x + 0.1 x² - 0.02 x³ with x = ln(E/1000 keV).
+    const PARAMS: [f64; 4] = [-3.0, -0.8, 0.1, -0.02];
+    const ENERGIES: [f64; 7] = [122.0, 245.0, 344.0, 662.0, 1173.0, 1332.0, 2614.0];
+
+    fn efficiency(energy: f64) -> f64 {
+        let x = (energy / 1000.0).ln();
+        PARAMS.iter().rev().fold(0.0, |acc, a| acc * x + a).exp()
+    }
+
+    fn point(energy: f64) -> CalibrationPoint {
+        CalibrationPoint {
+            source: 0,
+            from: 1,
+            to: 0,
+            energy,
+            emission: 1.0,
+            correction: 1.0,
+            efficiency: efficiency(energy),
+            defficiency: 0.01 * efficiency(energy),
+        }
+    }
+
+    #[test]
+    fn fit_recovers_polynomial() {
+        let points: Vec<_> = ENERGIES.iter().map(|&e| point(e)).collect();
+        let (model, chi2) = fit(&points, &CalibrationOptions::default()).unwrap();
+        assert!(chi2 < 1e-12, "chi2 = {chi2}");
+        for (a, b) in model.params.iter().zip(PARAMS) {
+            assert!((a - b).abs() < 1e-9, "{a} against {b}");
+        }
+    }
+
+    #[test]
+    fn fit_covariance() {
+        // For a straight line with weights w the covariance is
+        // [[Σwx², -Σwx], [-Σwx, Σw]] / (Σw Σwx² - (Σwx)²).
+        let points: Vec<_> = ENERGIES.iter().map(|&e| point(e)).collect();
+        let options = CalibrationOptions {
+            degree: 1,
+            ..Default::default()
+        };
+        let (model, chi2) = fit(&points, &options).unwrap();
+        // The cubic does not fit a line, so the covariance is scaled by χ²/ndf.
+        let scale = (chi2 / (points.len() - 2) as f64).max(1.0);
+        let (mut sw, mut swx, mut swx2) = (0.0, 0.0, 0.0);
+        for p in points.iter() {
+            let w = (p.efficiency / p.defficiency).powi(2);
+            let x = (p.energy / 1000.0).ln();
+            sw += w;
+            swx += w * x;
+            swx2 += w * x * x;
+        }
+        let det = sw * swx2 - swx * swx;
+        let expected = [swx2 / det, -swx / det, -swx / det, sw / det];
+        for (c, e) in model.covariance.iter().zip(expected) {
+            assert!((c - scale * e).abs() < 1e-9 * e.abs(), "{c} against {e}");
+        }
+    }
+
+    /// A source in which every level decays straight to the ground state, so
+    /// nothing sums.
+    fn single_lines_source() -> CalibrationSource {
+        let mut levels = vec![Level::new(0, 0.0, 0.0, 0.0, 0.0)];
+        let mut branches = Vec::new();
+        let mut observations = Vec::new();
+        let decays = 1e6;
+        let feeding = 1.0 / ENERGIES.len() as f64;
+        for (k, &e) in ENERGIES.iter().enumerate() {
+            levels.push(Level::new(k + 1, e, 0.0, feeding, 0.0));
+            branches.push(Branch::new(k + 1, 0, 1.0, 0.0, 0.0, 0.0));
+            let counts = decays * feeding * efficiency(e);
+            observations.push(Observation::new(k + 1, 0, counts, 0.01 * counts));
+        }
+        CalibrationSource {
+            name: "single lines".to_string(),
+            scheme: LevelScheme::new(levels, branches),
+            observations,
+            decay: Decay {
+                decays: Some((decays, 0.0)),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// ⁶⁰Co with a total efficiency of 0.2 at every energy: each peak loses
+    /// the share 0.2 of its counts to the other gamma ray.
+    fn co60_source() -> CalibrationSource {
+        let levels = vec![
+            Level::new(0, 0.0, 0.0, 0.0, 0.0),
+            Level::new(1, 1332.5, 0.0, 0.0, 0.0),
+            Level::new(2, 2505.7, 0.0, 1.0, 0.0),
+        ];
+        let branches = vec![
+            Branch::new(2, 1, 1.0, 0.0, 0.0, 0.0),
+            Branch::new(1, 0, 1.0, 0.0, 0.0, 0.0),
+        ];
+        let decays = 1e6;
+        let observations = [(2, 1, 1173.2), (1, 0, 1332.5)]
+            .iter()
+            .map(|&(j, i, e)| {
+                let counts = decays * efficiency(e) * (1.0 - 0.2);
+                Observation::new(j, i, counts, 0.01 * counts)
+            })
+            .collect();
+        CalibrationSource {
+            name: "60Co".to_string(),
+            scheme: LevelScheme::new(levels, branches),
+            observations,
+            decay: Decay {
+                decays: Some((decays, 0.0)),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn flat_total() -> TotalEfficiency {
+        let mut total = EfficiencyCurve::new(vec![50.0, 3000.0], vec![0.2, 0.2]);
+        total.interpolation = Interpolation::Linear;
+        TotalEfficiency::Direct(total.into())
+    }
+
+    #[test]
+    fn calibrate_without_summing() {
+        let total = EfficiencyCurve::new(vec![50.0, 1000.0, 3000.0], vec![0.3, 0.1, 0.05]);
+        let total = TotalEfficiency::Direct(total.into());
+        let calibration = calibrate(
+            &[single_lines_source()],
+            &total,
+            &CalibrationOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(calibration.iterations, 1);
+        for p in calibration.points.iter() {
+            assert!(
+                (p.correction - 1.0).abs() < 1e-12,
+                "correction {}",
+                p.correction
+            );
+        }
+        for (a, b) in calibration.model.params.iter().zip(PARAMS) {
+            assert!((a - b).abs() < 1e-9, "{a} against {b}");
+        }
+    }
+
+    #[test]
+    fn calibrate_removes_summing_bias() {
+        let sources = [single_lines_source(), co60_source()];
+        let calibration =
+            calibrate(&sources, &flat_total(), &CalibrationOptions::default()).unwrap();
+        assert!(calibration.iterations > 1);
+        let fitted = calibration.model.function();
+        for p in calibration.points.iter().filter(|p| p.source == 1) {
+            // The raw ⁶⁰Co points are 20 standard deviations low.
+            assert!((p.correction - 1.0 / 0.8).abs() < 1e-5, "{}", p.correction);
+            let truth = efficiency(p.energy);
+            let fit = fitted.eval(p.energy).unwrap();
+            assert!(
+                (fit - truth).abs() < 0.01 * p.defficiency,
+                "{fit} against {truth} ± {} at {} keV",
+                p.defficiency,
+                p.energy
+            );
+        }
+    }
+
+    #[test]
+    fn calibrate_reports_no_convergence() {
+        let sources = [single_lines_source(), co60_source()];
+        let options = CalibrationOptions {
+            max_iterations: 1,
+            ..Default::default()
+        };
+        match calibrate(&sources, &flat_total(), &options) {
+            Err(CalibrationError::NotConverged(c)) => assert_eq!(c.iterations, 1),
+            other => panic!("expected NotConverged, got {other:?}"),
+        }
+    }
+}
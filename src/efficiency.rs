@@ -1,61 +1,859 @@
+use crate::error::{LineParser, ParseError, ParseErrorKind, SampleError};
+use crate::level_info::{ELECTRON_MASS, LevelScheme, truncated_normal};
+use crate::parametric::{self, ParametricEfficiency, ParametricFunction};
+use rand::Rng;
+use std::fmt;
 use std::fs;
+use std::str::FromStr;
 
 /// This module handles the creating the splines for efficiency.
 use rgsl::interpolation;
 use rgsl::{Interp, InterpAccel, InterpType};
 
+/// How an efficiency curve is interpolated between the tabulated points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Interpolation {
+    Linear,
+    /// Natural cubic spline.
+    #[default]
+    Cubic,
+    Akima,
+    /// Steffen's monotone cubic, which never overshoots the tabulated points.
+    Steffen,
+    /// Cubic spline of log(efficiency) against log(energy).
+    LogLog,
+}
+
+impl Interpolation {
+    /// Fewest points the scheme can be built from.
+    pub fn min_size(&self) -> usize {
+        match self {
+            Interpolation::Linear => 2,
+            Interpolation::Cubic | Interpolation::LogLog => 3,
+            Interpolation::Akima => 5,
+            Interpolation::Steffen => 3,
+        }
+    }
+
+    fn interp_type(&self) -> Option<InterpType> {
+        match self {
+            Interpolation::Linear => Some(InterpType::linear()),
+            Interpolation::Cubic | Interpolation::LogLog => Some(InterpType::cspline()),
+            Interpolation::Akima => Some(InterpType::akima()),
+            Interpolation::Steffen => None,
+        }
+    }
+}
+
+impl fmt::Display for Interpolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Interpolation::Linear => "linear",
+            Interpolation::Cubic => "cubic",
+            Interpolation::Akima => "akima",
+            Interpolation::Steffen => "steffen",
+            Interpolation::LogLog => "loglog",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for Interpolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "linear" => Ok(Interpolation::Linear),
+            "cubic" | "cspline" => Ok(Interpolation::Cubic),
+            "akima" => Ok(Interpolation::Akima),
+            "steffen" => Ok(Interpolation::Steffen),
+            "loglog" | "log-log" => Ok(Interpolation::LogLog),
+            _ => Err(format!(
+                "unknown interpolation `{s}`, expected linear, cubic, akima, steffen or loglog"
+            )),
+        }
+    }
+}
+
+/// What to do with energies outside of the tabulated range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutOfRange {
+    /// Refuse, and name the transition.
+    #[default]
+    Error,
+    /// Use the efficiency at the nearest tabulated energy.
+    Clamp,
+    /// Continue a power law fit to the outermost points.
+    Extrapolate,
+}
+
+impl fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OutOfRange::Error => "error",
+            OutOfRange::Clamp => "clamp",
+            OutOfRange::Extrapolate => "extrapolate",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for OutOfRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(OutOfRange::Error),
+            "clamp" => Ok(OutOfRange::Clamp),
+            "extrapolate" => Ok(OutOfRange::Extrapolate),
+            _ => Err(format!(
+                "unknown out of range policy `{s}`, expected error, clamp or extrapolate"
+            )),
+        }
+    }
+}
+
+/// Number of points at each end of the table used for the extrapolation fit.
+const TAIL_POINTS: usize = 3;
+
+/// Least squares fit of ln(eff) = a + b ln(E) to the given points, or of
+/// eff = a + b E if any of them is not positive.
+#[derive(Debug, Clone, Copy)]
+struct Tail {
+    log: bool,
+    a: f64,
+    b: f64,
+}
+
+impl Tail {
+    fn fit(energies: &[f64], eff: &[f64]) -> Self {
+        let log = energies.iter().chain(eff.iter()).all(|&v| v > 0.0);
+        let t = |v: f64| if log { v.ln() } else { v };
+        let n = energies.len() as f64;
+        let mx = energies.iter().map(|&e| t(e)).sum::<f64>() / n;
+        let my = eff.iter().map(|&e| t(e)).sum::<f64>() / n;
+        let sxy: f64 = energies
+            .iter()
+            .zip(eff.iter())
+            .map(|(&e, &y)| (t(e) - mx) * (t(y) - my))
+            .sum();
+        let sxx: f64 = energies.iter().map(|&e| (t(e) - mx).powi(2)).sum();
+        let b = sxy / sxx;
+        Self {
+            log,
+            a: my - b * mx,
+            b,
+        }
+    }
+
+    fn eval(&self, energy: f64) -> f64 {
+        if self.log {
+            (self.a + self.b * energy.ln()).exp()
+        } else {
+            self.a + self.b * energy
+        }
+    }
+}
+
+/// The tabulated points, energy (keV) and efficiency, of an efficiency curve.
+/// Each point can have an uncertainty, `deff`, and the whole curve a relative
+/// scale uncertainty, `scale_unc`, that is fully correlated between points.
+/// This is plain data that can be shared between threads, each of which builds
+/// its own spline and accelerator with `spline`.
+#[derive(Debug, Clone)]
+pub struct EfficiencyCurve {
+    pub energies: Vec<f64>,
+    pub eff: Vec<f64>,
+    pub deff: Vec<f64>,
+    pub scale_unc: f64,
+    pub interpolation: Interpolation,
+    pub out_of_range: OutOfRange,
+}
+
+impl EfficiencyCurve {
+    pub fn new(energies: Vec<f64>, eff: Vec<f64>) -> Self {
+        let deff = vec![0.0; eff.len()];
+        Self {
+            energies,
+            eff,
+            deff,
+            scale_unc: 0.0,
+            interpolation: Interpolation::default(),
+            out_of_range: OutOfRange::default(),
+        }
+    }
+
+    pub fn spline(&self) -> Efficiency {
+        Efficiency::new(
+            self.energies.clone(),
+            self.eff.clone(),
+            self.interpolation,
+            self.out_of_range,
+        )
+    }
+
+    /// Lowest and highest tabulated energy.
+    pub fn range(&self) -> (f64, f64) {
+        (self.energies[0], *self.energies.last().unwrap())
+    }
+
+    pub fn contains(&self, energy: f64) -> bool {
+        let (low, high) = self.range();
+        (low..=high).contains(&energy)
+    }
+
+    /// Whether the curve has any uncertainty to sample.
+    pub fn is_uncertain(&self) -> bool {
+        self.scale_unc > 0.0 || self.deff.iter().any(|&d| d > 0.0)
+    }
+
+    /// Draw the efficiency at each tabulated energy, with one common scale factor.
+    pub fn sample_eff<R: Rng + ?Sized>(&self, r: &mut R) -> Result<Vec<f64>, SampleError> {
+        let scale = truncated_normal(1.0, self.scale_unc, r)?;
+        self.eff
+            .iter()
+            .zip(self.deff.iter())
+            .map(|(&e, &de)| Ok(scale * truncated_normal(e, de, r)?))
+            .collect()
+    }
+}
+
+enum Interpolator {
+    Gsl(Interp),
+    /// Slopes at each point for the Hermite cubic of Steffen's method.
+    Steffen(Vec<f64>),
+}
+
+/// An efficiency curve with its interpolation. For `Interpolation::LogLog`
+/// the stored points are the logarithms of the tabulated ones.
 pub struct Efficiency {
     pub energies: Vec<f64>,
     pub eff: Vec<f64>,
-    interp: Interp,
+    pub interpolation: Interpolation,
+    pub out_of_range: OutOfRange,
+    range: (f64, f64),
+    // Fits to the low and high energy ends for OutOfRange::Extrapolate.
+    tails: Option<(Tail, Tail)>,
+    interp: Interpolator,
     interp_acc: InterpAccel,
 }
 
 impl Efficiency {
-    #[allow(unused_mut)]
-    fn new(energies: Vec<f64>, eff: Vec<f64>) -> Self {
-        let interp_type = InterpType::cspline();
-        let mut interp =
-            Interp::new(interp_type, energies.len()).expect("Failed to initialize cubic spline");
-        interp
-            .init(&energies, &eff)
-            .expect("Failed to initialize spline!");
-        let mut interp_acc = InterpAccel::new();
-        Self {
+    pub fn new(
+        energies: Vec<f64>,
+        eff: Vec<f64>,
+        interpolation: Interpolation,
+        out_of_range: OutOfRange,
+    ) -> Self {
+        let range = (energies[0], *energies.last().unwrap());
+        let energies = match interpolation {
+            Interpolation::LogLog => energies.iter().map(|e| e.ln()).collect(),
+            _ => energies,
+        };
+        let interp = match interpolation.interp_type() {
+            Some(t) => Interpolator::Gsl(
+                Interp::new(t, energies.len()).expect("Failed to initialize interpolation"),
+            ),
+            None => Interpolator::Steffen(Vec::new()),
+        };
+        let mut efficiency = Self {
             energies,
-            eff,
+            eff: Vec::new(),
+            interpolation,
+            out_of_range,
+            range,
+            tails: None,
             interp,
-            interp_acc,
+            interp_acc: InterpAccel::new(),
+        };
+        efficiency.set_eff(eff);
+        efficiency
+    }
+
+    /// Replace the tabulated efficiencies, keeping the energies.
+    pub fn set_eff(&mut self, eff: Vec<f64>) {
+        if self.out_of_range == OutOfRange::Extrapolate {
+            let energies: Vec<f64> = match self.interpolation {
+                Interpolation::LogLog => self.energies.iter().map(|e| e.exp()).collect(),
+                _ => self.energies.clone(),
+            };
+            let n = energies.len();
+            let k = TAIL_POINTS.min(n);
+            self.tails = Some((
+                Tail::fit(&energies[..k], &eff[..k]),
+                Tail::fit(&energies[n - k..], &eff[n - k..]),
+            ));
+        }
+        self.eff = match self.interpolation {
+            Interpolation::LogLog => eff.iter().map(|e| e.ln()).collect(),
+            _ => eff,
+        };
+        match &mut self.interp {
+            Interpolator::Gsl(interp) => interp
+                .init(&self.energies, &self.eff)
+                .expect("Failed to initialize spline!"),
+            Interpolator::Steffen(slopes) => *slopes = steffen_slopes(&self.energies, &self.eff),
+        }
+    }
+
+    /// Lowest and highest tabulated energy.
+    pub fn range(&self) -> (f64, f64) {
+        self.range
+    }
+
+    /// Efficiency at `energy`. Outside of the tabulated range the
+    /// `out_of_range` policy applies, and `OutOfRange::Error` gives `None`.
+    pub fn eval(&mut self, energy: f64) -> Option<f64> {
+        let (low, high) = self.range;
+        let energy = if energy < low || energy > high {
+            match (self.out_of_range, self.tails) {
+                (OutOfRange::Error, _) => return None,
+                (OutOfRange::Extrapolate, Some((low_tail, high_tail))) => {
+                    let tail = if energy < low { low_tail } else { high_tail };
+                    return Some(tail.eval(energy));
+                }
+                _ => energy.clamp(low, high),
+            }
+        } else {
+            energy
+        };
+        Some(self.interpolate(energy))
+    }
+
+    fn interpolate(&mut self, energy: f64) -> f64 {
+        let x = match self.interpolation {
+            Interpolation::LogLog => energy.ln(),
+            _ => energy,
+        };
+        let y = match &self.interp {
+            Interpolator::Gsl(interp) => {
+                interpolation::eval(interp, &self.energies, &self.eff, x, &mut self.interp_acc)
+            }
+            Interpolator::Steffen(slopes) => {
+                let i = self.interp_acc.find(&self.energies, x);
+                hermite(&self.energies, &self.eff, slopes, i, x)
+            }
+        };
+        match self.interpolation {
+            Interpolation::LogLog => y.exp(),
+            _ => y,
+        }
+    }
+}
+
+/// Slopes of the monotone cubic of Steffen, A&A 239 (1990) 443.
+fn steffen_slopes(x: &[f64], y: &[f64]) -> Vec<f64> {
+    let n = x.len();
+    let h: Vec<f64> = x.windows(2).map(|w| w[1] - w[0]).collect();
+    let s: Vec<f64> = (0..n - 1).map(|i| (y[i + 1] - y[i]) / h[i]).collect();
+    let mut slopes = vec![0.0; n];
+    for i in 1..n - 1 {
+        let p = (s[i - 1] * h[i] + s[i] * h[i - 1]) / (h[i - 1] + h[i]);
+        slopes[i] =
+            (s[i - 1].signum() + s[i].signum()) * s[i - 1].abs().min(s[i].abs()).min(0.5 * p.abs());
+    }
+    // One sided parabolas at the ends, limited so they keep the sign of the
+    // first and last secant.
+    let end_slope = |s0: f64, s1: f64, h0: f64, h1: f64| {
+        let p = s0 * (1.0 + h0 / (h0 + h1)) - s1 * h0 / (h0 + h1);
+        if p * s0 <= 0.0 {
+            0.0
+        } else if p.abs() > 2.0 * s0.abs() {
+            2.0 * s0
+        } else {
+            p
+        }
+    };
+    slopes[0] = end_slope(s[0], s[1], h[0], h[1]);
+    slopes[n - 1] = end_slope(s[n - 2], s[n - 3], h[n - 2], h[n - 3]);
+    slopes
+}
+
+/// Cubic Hermite interpolation on the interval starting at index `i`.
+fn hermite(x: &[f64], y: &[f64], slopes: &[f64], i: usize, at: f64) -> f64 {
+    let h = x[i + 1] - x[i];
+    let s = (y[i + 1] - y[i]) / h;
+    let t = at - x[i];
+    let c = (3.0 * s - 2.0 * slopes[i] - slopes[i + 1]) / h;
+    let d = (slopes[i] + slopes[i + 1] - 2.0 * s) / (h * h);
+    y[i] + t * (slopes[i] + t * (c + t * d))
+}
+
+/// An efficiency given either as a table or as a parametric function, or the
+/// sum of several, e.g. of the crystals of an add-back group. This is plain
+/// data, each thread builds its own evaluator with `evaluator`.
+#[derive(Debug, Clone)]
+pub enum EfficiencyModel {
+    Tabulated(EfficiencyCurve),
+    Parametric(ParametricEfficiency),
+    /// The parts with a relative uncertainty on the scale of the sum, which
+    /// is common to every part.
+    Sum {
+        models: Vec<EfficiencyModel>,
+        scale_unc: f64,
+    },
+}
+
+impl EfficiencyModel {
+    pub fn evaluator(&self) -> EfficiencyEvaluator {
+        match self {
+            EfficiencyModel::Tabulated(c) => EfficiencyEvaluator::Spline(c.spline()),
+            EfficiencyModel::Parametric(p) => EfficiencyEvaluator::Function(p.function()),
+            EfficiencyModel::Sum { models, .. } => EfficiencyEvaluator::Sum {
+                parts: models.iter().map(|m| m.evaluator()).collect(),
+                scale: 1.0,
+            },
+        }
+    }
+
+    /// Whether the model has any uncertainty to sample.
+    pub fn is_uncertain(&self) -> bool {
+        match self {
+            EfficiencyModel::Tabulated(c) => c.is_uncertain(),
+            EfficiencyModel::Parametric(p) => p.is_uncertain(),
+            EfficiencyModel::Sum { models, scale_unc } => {
+                *scale_unc > 0.0 || models.iter().any(|m| m.is_uncertain())
+            }
+        }
+    }
+
+    /// Draw a new efficiency curve into `evaluator`, which has to come from
+    /// this model.
+    pub fn resample<R: Rng + ?Sized>(
+        &self,
+        evaluator: &mut EfficiencyEvaluator,
+        r: &mut R,
+    ) -> Result<(), SampleError> {
+        match (self, evaluator) {
+            (EfficiencyModel::Tabulated(c), EfficiencyEvaluator::Spline(s)) => {
+                s.set_eff(c.sample_eff(r)?)
+            }
+            (EfficiencyModel::Parametric(p), EfficiencyEvaluator::Function(f)) => {
+                let (params, scale) = p.sample_params(r)?;
+                f.set_params(params, scale);
+            }
+            (
+                EfficiencyModel::Sum { models, scale_unc },
+                EfficiencyEvaluator::Sum { parts, scale },
+            ) => {
+                for (m, e) in models.iter().zip(parts.iter_mut()) {
+                    if m.is_uncertain() {
+                        m.resample(e, r)?;
+                    }
+                }
+                *scale = truncated_normal(1.0, *scale_unc, r)?;
+            }
+            _ => panic!("efficiency evaluator does not belong to this model"),
+        }
+        Ok(())
+    }
+
+    /// Lowest and highest energy the model covers, for a sum the range that
+    /// every part covers.
+    pub fn range(&self) -> (f64, f64) {
+        match self {
+            EfficiencyModel::Tabulated(c) => c.range(),
+            EfficiencyModel::Parametric(p) => p.range(),
+            EfficiencyModel::Sum { models, .. } => common_range(models.iter().map(|m| m.range())),
+        }
+    }
+
+    pub fn contains(&self, energy: f64) -> bool {
+        let (low, high) = self.range();
+        (low..=high).contains(&energy)
+    }
+
+    /// The policy of the model, for a sum the one of its first part.
+    pub fn out_of_range(&self) -> OutOfRange {
+        match self {
+            EfficiencyModel::Tabulated(c) => c.out_of_range,
+            EfficiencyModel::Parametric(p) => p.out_of_range,
+            EfficiencyModel::Sum { models, .. } => models
+                .first()
+                .map_or(OutOfRange::Error, |m| m.out_of_range()),
+        }
+    }
+
+    pub fn set_out_of_range(&mut self, out_of_range: OutOfRange) {
+        match self {
+            EfficiencyModel::Tabulated(c) => c.out_of_range = out_of_range,
+            EfficiencyModel::Parametric(p) => p.out_of_range = out_of_range,
+            EfficiencyModel::Sum { models, .. } => models
+                .iter_mut()
+                .for_each(|m| m.set_out_of_range(out_of_range)),
+        }
+    }
+
+    /// Set the relative uncertainty on the overall scale, for a sum on the
+    /// sum itself, so that one scale is drawn for all of its parts.
+    pub fn set_scale_unc(&mut self, scale_unc: f64) {
+        match self {
+            EfficiencyModel::Tabulated(c) => c.scale_unc = scale_unc,
+            EfficiencyModel::Parametric(p) => p.scale_unc = scale_unc,
+            EfficiencyModel::Sum { scale_unc: s, .. } => *s = scale_unc,
+        }
+    }
+}
+
+/// The part of the energy axis that every range covers.
+fn common_range(ranges: impl Iterator<Item = (f64, f64)>) -> (f64, f64) {
+    ranges.fold((f64::NEG_INFINITY, f64::INFINITY), |(lo, hi), (l, h)| {
+        (lo.max(l), hi.min(h))
+    })
+}
+
+impl From<EfficiencyCurve> for EfficiencyModel {
+    fn from(curve: EfficiencyCurve) -> Self {
+        EfficiencyModel::Tabulated(curve)
+    }
+}
+
+impl From<ParametricEfficiency> for EfficiencyModel {
+    fn from(model: ParametricEfficiency) -> Self {
+        EfficiencyModel::Parametric(model)
+    }
+}
+
+impl fmt::Display for EfficiencyModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EfficiencyModel::Tabulated(c) => write!(f, "interpolation {}", c.interpolation),
+            EfficiencyModel::Parametric(p) => write!(
+                f,
+                "{} model with {} parameters, E0 {} keV",
+                p.form,
+                p.params.len(),
+                p.e0
+            ),
+            EfficiencyModel::Sum { models, .. } => write!(f, "sum of {} curves", models.len()),
+        }
+    }
+}
+
+/// Evaluates an `EfficiencyModel` for one thread.
+pub enum EfficiencyEvaluator {
+    Spline(Efficiency),
+    Function(ParametricFunction),
+    /// The parts and the drawn scale of their sum.
+    Sum {
+        parts: Vec<EfficiencyEvaluator>,
+        scale: f64,
+    },
+}
+
+impl EfficiencyEvaluator {
+    /// Efficiency at `energy`, `None` if it is out of range and the model
+    /// refuses to handle it.
+    pub fn eval(&mut self, energy: f64) -> Option<f64> {
+        match self {
+            EfficiencyEvaluator::Spline(s) => s.eval(energy),
+            EfficiencyEvaluator::Function(f) => f.eval(energy),
+            EfficiencyEvaluator::Sum { parts, scale } => parts
+                .iter_mut()
+                .map(|p| p.eval(energy))
+                .sum::<Option<f64>>()
+                .map(|e| *scale * e),
+        }
+    }
+
+    pub fn range(&self) -> (f64, f64) {
+        match self {
+            EfficiencyEvaluator::Spline(s) => s.range(),
+            EfficiencyEvaluator::Function(f) => f.range(),
+            EfficiencyEvaluator::Sum { parts, .. } => common_range(parts.iter().map(|p| p.range())),
+        }
+    }
+}
+
+/// Where the total efficiency comes from: its own model, or the peak
+/// efficiency divided by a peak-to-total ratio model.
+#[derive(Debug, Clone)]
+pub enum TotalEfficiency {
+    Direct(EfficiencyModel),
+    PeakToTotal(EfficiencyModel),
+}
+
+impl TotalEfficiency {
+    /// The total efficiency or the peak-to-total ratio, whichever is given.
+    pub fn model(&self) -> &EfficiencyModel {
+        match self {
+            TotalEfficiency::Direct(m) | TotalEfficiency::PeakToTotal(m) => m,
+        }
+    }
+
+    pub fn model_mut(&mut self) -> &mut EfficiencyModel {
+        match self {
+            TotalEfficiency::Direct(m) | TotalEfficiency::PeakToTotal(m) => m,
+        }
+    }
+
+    /// Name of the curve in messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TotalEfficiency::Direct(_) => "total",
+            TotalEfficiency::PeakToTotal(_) => "peak-to-total",
+        }
+    }
+
+    pub fn evaluator(&self) -> TotalEvaluator {
+        TotalEvaluator {
+            evaluator: self.model().evaluator(),
+            peak_to_total: matches!(self, TotalEfficiency::PeakToTotal(_)),
+            annihilation: None,
+            veto: None,
+            suppressed: false,
+        }
+    }
+
+    /// Draw a new curve into `evaluator`. For a peak-to-total ratio this only
+    /// draws the ratio, the peak efficiency is drawn with the peak model.
+    pub fn resample<R: Rng + ?Sized>(
+        &self,
+        evaluator: &mut TotalEvaluator,
+        r: &mut R,
+    ) -> Result<(), SampleError> {
+        self.model().resample(&mut evaluator.evaluator, r)
+    }
+}
+
+impl From<EfficiencyModel> for TotalEfficiency {
+    fn from(model: EfficiencyModel) -> Self {
+        TotalEfficiency::Direct(model)
+    }
+}
+
+impl fmt::Display for TotalEfficiency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TotalEfficiency::Direct(m) => write!(f, "{m}"),
+            TotalEfficiency::PeakToTotal(m) => write!(f, "peak / peak-to-total, {m}"),
+        }
+    }
+}
+
+/// Evaluates a `TotalEfficiency` for one thread.
+pub struct TotalEvaluator {
+    pub evaluator: EfficiencyEvaluator,
+    peak_to_total: bool,
+    /// Total efficiency of a 511 keV annihilation photon in place of the
+    /// curve, for sources that positrons can escape before annihilating.
+    pub annihilation: Option<f64>,
+    /// Efficiency of an anti-Compton shield around the detector. A photon that
+    /// reaches the shield vetoes the event, which is lost from the suppressed
+    /// spectrum like one that reaches the detector.
+    pub veto: Option<EfficiencyEvaluator>,
+    /// Whether `eval` adds the veto efficiency, for the suppressed spectrum.
+    pub suppressed: bool,
+}
+
+impl TotalEvaluator {
+    /// Total efficiency at `energy`, where the peak efficiency is `peak`, plus
+    /// the veto efficiency in the suppressed spectrum.
+    pub fn eval(&mut self, energy: f64, peak: f64) -> Option<f64> {
+        let value = self.evaluator.eval(energy)?;
+        let total = if self.peak_to_total {
+            peak / value
+        } else {
+            value
+        };
+        Some(total + self.veto_at(energy)?)
+    }
+
+    /// Veto efficiency at `energy`, zero outside of the suppressed spectrum.
+    pub fn veto_at(&mut self, energy: f64) -> Option<f64> {
+        match (&mut self.veto, self.suppressed) {
+            (Some(veto), true) => veto.eval(energy),
+            _ => Some(0.0),
         }
     }
 
-    pub fn eval(&mut self, energy: f64) -> f64 {
-        interpolation::eval(
-            &self.interp,
-            &self.energies,
-            &self.eff,
-            energy,
-            &mut self.interp_acc,
+    /// The range of the curve, and of the veto curve in the suppressed spectrum.
+    pub fn range(&self) -> (f64, f64) {
+        match (&self.veto, self.suppressed) {
+            (Some(veto), true) => common_range([self.evaluator.range(), veto.range()].into_iter()),
+            _ => self.evaluator.range(),
+        }
+    }
+
+    /// Whether `eval` needs the peak efficiency.
+    pub fn needs_peak(&self) -> bool {
+        self.peak_to_total
+    }
+
+    /// Name of the curve in messages.
+    pub fn name(&self) -> &'static str {
+        match (self.peak_to_total, self.veto.is_some() && self.suppressed) {
+            (true, false) => "peak-to-total",
+            (true, true) => "peak-to-total or veto",
+            (false, false) => "total",
+            (false, true) => "total or veto",
+        }
+    }
+}
+
+/// Every photon of the nominal level scheme, as a description and its energy,
+/// that lies outside of the range of `model`: each transition, the X-ray
+/// lines if `xrays` and the annihilation photons if `annihilation`.
+pub fn transitions_out_of_range(
+    scheme: &LevelScheme,
+    model: &EfficiencyModel,
+    xrays: bool,
+    annihilation: bool,
+) -> Vec<(String, f64)> {
+    let transitions = scheme.branches.iter().map(|b| {
+        (
+            format!("{} -> {}", b.from, b.to),
+            scheme.levels[b.from].energy - scheme.levels[b.to].energy,
         )
+    });
+    let xray_lines = scheme
+        .atomic
+        .xrays
+        .iter()
+        .filter(|_| xrays)
+        .map(|x| (format!("{} X-ray", x.shell), x.energy));
+    let annihilation_line = (annihilation && !scheme.annihilation.is_empty())
+        .then(|| ("annihilation".to_string(), ELECTRON_MASS));
+    transitions
+        .chain(xray_lines)
+        .chain(annihilation_line)
+        .filter(|(_, e)| !model.contains(*e))
+        .collect()
+}
+
+/// Read an efficiency file, which is parametric if it starts with a `Model`
+/// section (see `parametric::read_parametric`) and tabulated otherwise (see
+/// `make_efficiency`). `interpolation` only applies to tables.
+pub fn read_efficiency(
+    file_path: &str,
+    interpolation: Option<Interpolation>,
+) -> Result<EfficiencyModel, ParseError> {
+    let file_content = fs::read_to_string(file_path).map_err(|e| ParseError::read(file_path, e))?;
+    if parametric::is_parametric(&file_content) {
+        parametric::read_parametric(file_path).map(EfficiencyModel::from)
+    } else {
+        make_efficiency(file_path, interpolation).map(EfficiencyModel::from)
     }
 }
-pub fn make_efficiency(file_path: &str) -> Efficiency {
-    let file_content =
-        fs::read_to_string(file_path).expect(format!("Failed to read: {file_path}! \n").as_str());
+
+/// Read a two column (energy, efficiency) or three column (energy, efficiency,
+/// uncertainty) file. Lines starting with `#` are comments, except for
+/// `# interpolation: <scheme>` which selects the interpolation. `interpolation`
+/// overrides that header, and without either the curve is a cubic spline.
+pub fn make_efficiency(
+    file_path: &str,
+    interpolation: Option<Interpolation>,
+) -> Result<EfficiencyCurve, ParseError> {
+    let file_content = fs::read_to_string(file_path).map_err(|e| ParseError::read(file_path, e))?;
 
     let mut energies: Vec<f64> = Vec::new();
     let mut eff: Vec<f64> = Vec::new();
-    for line in file_content.lines() {
-        let nums: Vec<f64> = line
-            .split_whitespace()
-            .map(|p| {
-                p.parse()
-                    .expect("Failed to parse line in efficiency file!\n")
+    let mut deff: Vec<f64> = Vec::new();
+    let mut header_interpolation = None;
+    for (i, line) in file_content.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        if let Some(comment) = trimmed.strip_prefix('#') {
+            if let Some(scheme) = comment.trim().strip_prefix("interpolation:") {
+                let scheme = scheme.trim();
+                header_interpolation = Some(scheme.parse().map_err(|_| {
+                    ParseError::new(
+                        file_path,
+                        i + 1,
+                        "efficiency",
+                        scheme,
+                        ParseErrorKind::Invalid("interpolation"),
+                    )
+                })?);
+            }
+            continue;
+        }
+        let mut p = LineParser::new(file_path, i + 1, "efficiency", trimmed);
+        let energy: f64 = p.next("energy")?;
+        if energies.last().is_some_and(|&last| energy <= last) {
+            return Err(p.error(&energy.to_string(), ParseErrorKind::NotIncreasing));
+        }
+        energies.push(energy);
+        let value: f64 = p.next("efficiency")?;
+        if value < 0.0 {
+            return Err(p.error(&value.to_string(), ParseErrorKind::Negative("efficiency")));
+        }
+        eff.push(value);
+        deff.push(p.uncertainty("efficiency uncertainty")?.unwrap_or(0.0));
+    }
+
+    let interpolation = interpolation.or(header_interpolation).unwrap_or_default();
+    let min_size = interpolation.min_size();
+    if energies.len() < min_size {
+        return Err(ParseError::new(
+            file_path,
+            0,
+            "efficiency",
+            &energies.len().to_string(),
+            ParseErrorKind::TooFewPoints(min_size),
+        ));
+    }
+    if interpolation == Interpolation::LogLog
+        && let Some(k) = (0..energies.len()).find(|&k| energies[k] <= 0.0 || eff[k] <= 0.0)
+    {
+        return Err(ParseError::new(
+            file_path,
+            0,
+            "efficiency",
+            &format!("{} {}", energies[k], eff[k]),
+            ParseErrorKind::NotPositive,
+        ));
+    }
+
+    let mut curve = EfficiencyCurve::new(energies, eff);
+    curve.deff = deff;
+    curve.interpolation = interpolation;
+    Ok(curve)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The Steffen curve on a fine grid over every interval.
+    fn steffen_curve(x: &[f64], y: &[f64]) -> Vec<(usize, f64)> {
+        let slopes = steffen_slopes(x, y);
+        (0..x.len() - 1)
+            .flat_map(|i| {
+                let slopes = &slopes;
+                (0..=100).map(move |k| {
+                    let at = x[i] + (x[i + 1] - x[i]) * k as f64 / 100.0;
+                    (i, hermite(x, y, slopes, i, at))
+                })
             })
-            .collect();
-        energies.push(nums[0]);
-        eff.push(nums[1]);
+            .collect()
     }
 
-    Efficiency::new(energies, eff)
+    #[test]
+    fn steffen_passes_through_points() {
+        let x = [50.0, 100.0, 200.0, 400.0, 800.0, 1600.0];
+        let y = [0.02, 0.08, 0.06, 0.035, 0.02, 0.011];
+        let slopes = steffen_slopes(&x, &y);
+        for i in 0..x.len() - 1 {
+            assert!((hermite(&x, &y, &slopes, i, x[i]) - y[i]).abs() < 1e-15);
+            assert!((hermite(&x, &y, &slopes, i, x[i + 1]) - y[i + 1]).abs() < 1e-15);
+        }
+    }
+
+    #[test]
+    fn steffen_does_not_overshoot() {
+        // Rises to a maximum and falls again, with a flat step, like a peak efficiency.
+        let x = [40.0, 60.0, 80.0, 120.0, 150.0, 300.0, 600.0, 1400.0];
+        let y = [0.01, 0.07, 0.09, 0.092, 0.092, 0.05, 0.03, 0.014];
+        for (i, v) in steffen_curve(&x, &y) {
+            let (lo, hi) = (y[i].min(y[i + 1]), y[i].max(y[i + 1]));
+            assert!(
+                lo - 1e-15 <= v && v <= hi + 1e-15,
+                "{v} outside of [{lo}, {hi}] between points {i} and {}",
+                i + 1
+            );
+        }
+    }
 }
@@ -0,0 +1,310 @@
+/// This module defines the errors returned when an input or efficiency file
+/// can not be parsed, or when an efficiency curve does not cover a transition.
+use std::fmt;
+use std::io;
+use std::str::{FromStr, SplitWhitespace};
+
+#[derive(Debug)]
+pub enum ParseErrorKind {
+    /// The file could not be read at all.
+    Read(io::Error),
+    /// The line is not one of the known section headers.
+    UnknownSection,
+    /// The line ended before this field.
+    Missing(&'static str),
+    /// The token for this field is not a valid number.
+    Invalid(&'static str),
+    /// A branch or observation refers to a level that does not exist.
+    UnknownLevel(usize),
+    /// A branch that does not go down in energy.
+    NotDownward(usize, usize),
+    /// A transition is given more than once in a section that allows one line per transition.
+    DuplicateTransition(usize, usize),
+    /// A line refers to a transition that is not in the B-Values section.
+    UnknownTransition(usize, usize),
+    /// Efficiency energies have to be strictly increasing.
+    NotIncreasing,
+    /// Not enough points to build the interpolation.
+    TooFewPoints(usize),
+    /// Log-log interpolation needs positive energies and efficiencies.
+    NotPositive,
+    /// This field, e.g. an uncertainty, can not be negative.
+    Negative(&'static str),
+    /// This many values were expected.
+    Count(usize),
+    /// The line does not have one of the allowed numbers of fields.
+    Fields(&'static str),
+    /// The covariance matrix is not symmetric positive semi-definite.
+    NotCovariance,
+    /// An add-back group refers to a detector that is not in the Detectors section.
+    UnknownDetector(String),
+    /// An array file without any detector.
+    NoDetectors,
+    /// The voxel weights do not add up to a positive total.
+    ZeroWeight,
+}
+
+/// Where and why parsing failed. `line` is 1-based and is 0 when the error
+/// concerns the file as a whole.
+#[derive(Debug)]
+pub struct ParseError {
+    pub file: String,
+    pub line: usize,
+    pub section: String,
+    pub token: String,
+    pub kind: ParseErrorKind,
+}
+
+impl ParseError {
+    pub fn new(file: &str, line: usize, section: &str, token: &str, kind: ParseErrorKind) -> Self {
+        Self {
+            file: file.to_string(),
+            line,
+            section: section.to_string(),
+            token: token.to_string(),
+            kind,
+        }
+    }
+
+    pub fn read(file: &str, err: io::Error) -> Self {
+        Self::new(file, 0, "", "", ParseErrorKind::Read(err))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let ParseErrorKind::Read(e) = &self.kind {
+            return write!(f, "Failed to read {}: {e}", self.file);
+        }
+        write!(f, "{}:{}: ", self.file, self.line)?;
+        if !self.section.is_empty() {
+            write!(f, "[{}] ", self.section)?;
+        }
+        match &self.kind {
+            ParseErrorKind::Read(_) => unreachable!(),
+            ParseErrorKind::UnknownSection => {
+                write!(f, "`{}` is not a valid section header", self.token)
+            }
+            ParseErrorKind::Missing(field) => write!(f, "missing {field} in `{}`", self.token),
+            ParseErrorKind::Invalid(field) => {
+                write!(f, "unable to parse {field} from `{}`", self.token)
+            }
+            ParseErrorKind::UnknownLevel(idx) => write!(
+                f,
+                "level {idx} is not defined in the Energy-Levels section (`{}`)",
+                self.token
+            ),
+            ParseErrorKind::NotDownward(from, to) => {
+                write!(f, "level {from} is not above level {to} (`{}`)", self.token)
+            }
+            ParseErrorKind::DuplicateTransition(from, to) => write!(
+                f,
+                "transition {from} -> {to} is already given in this section (`{}`)",
+                self.token
+            ),
+            ParseErrorKind::UnknownTransition(from, to) => write!(
+                f,
+                "transition {from} -> {to} is not defined in the B-Values section (`{}`)",
+                self.token
+            ),
+            ParseErrorKind::NotIncreasing => {
+                write!(
+                    f,
+                    "energy `{}` is not larger than the previous one",
+                    self.token
+                )
+            }
+            ParseErrorKind::NotPositive => write!(
+                f,
+                "log-log interpolation needs positive values, found `{}`",
+                self.token
+            ),
+            ParseErrorKind::Negative(field) => {
+                write!(f, "{field} can not be negative, found `{}`", self.token)
+            }
+            ParseErrorKind::TooFewPoints(n) => {
+                write!(f, "at least {n} points are needed, found {}", self.token)
+            }
+            ParseErrorKind::Count(n) => write!(f, "expected {n}, found {}", self.token),
+            ParseErrorKind::Fields(n) => write!(f, "expected {n} fields in `{}`", self.token),
+            ParseErrorKind::NotCovariance => write!(
+                f,
+                "`{}` is not a symmetric positive semi-definite matrix",
+                self.token
+            ),
+            ParseErrorKind::UnknownDetector(name) => write!(
+                f,
+                "detector `{name}` is not defined in the Detectors section (`{}`)",
+                self.token
+            ),
+            ParseErrorKind::NoDetectors => write!(f, "no detectors are defined"),
+            ParseErrorKind::ZeroWeight => write!(
+                f,
+                "the voxel weights add up to {}, at least one has to be positive",
+                self.token
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ParseErrorKind::Read(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A photon energy lies outside of the tabulated range of an efficiency
+/// curve whose out of range policy is `OutOfRange::Error`.
+#[derive(Debug, Clone)]
+pub struct EfficiencyRangeError {
+    /// Which curve, e.g. "peak" or "total".
+    pub curve: String,
+    /// Which photon, e.g. "transition 2 -> 1" or "K X-ray".
+    pub what: String,
+    pub energy: f64,
+    pub range: (f64, f64),
+}
+
+impl fmt::Display for EfficiencyRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({:.2} keV) is outside of the {} efficiency range {} to {} keV",
+            self.what, self.energy, self.curve, self.range.0, self.range.1
+        )
+    }
+}
+
+impl std::error::Error for EfficiencyRangeError {}
+
+/// A value with an uncertainty can not be drawn from a normal distribution
+/// truncated at zero, because the value or the uncertainty is negative.
+#[derive(Debug, Clone)]
+pub struct SampleError {
+    pub value: f64,
+    pub std: f64,
+}
+
+impl fmt::Display for SampleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "can not sample {} ± {}, values with an uncertainty and uncertainties have to be positive",
+            self.value, self.std
+        )
+    }
+}
+
+impl std::error::Error for SampleError {}
+
+/// Why a Monte Carlo run stopped.
+#[derive(Debug, Clone)]
+pub enum RunError {
+    Range(EfficiencyRangeError),
+    Sample(SampleError),
+}
+
+impl From<EfficiencyRangeError> for RunError {
+    fn from(e: EfficiencyRangeError) -> Self {
+        RunError::Range(e)
+    }
+}
+
+impl From<SampleError> for RunError {
+    fn from(e: SampleError) -> Self {
+        RunError::Sample(e)
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Range(e) => e.fmt(f),
+            RunError::Sample(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RunError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RunError::Range(e) => Some(e),
+            RunError::Sample(e) => Some(e),
+        }
+    }
+}
+
+/// Splits a line into whitespace separated fields and reports failures with
+/// the file, line and section they came from.
+pub(crate) struct LineParser<'a> {
+    file: &'a str,
+    line_no: usize,
+    section: &'a str,
+    line: &'a str,
+    parts: SplitWhitespace<'a>,
+}
+
+impl<'a> LineParser<'a> {
+    pub fn new(file: &'a str, line_no: usize, section: &'a str, line: &'a str) -> Self {
+        Self {
+            file,
+            line_no,
+            section,
+            line,
+            parts: line.split_whitespace(),
+        }
+    }
+
+    /// Total number of fields on the line.
+    pub fn n_fields(&self) -> usize {
+        self.line.split_whitespace().count()
+    }
+
+    /// The whole line.
+    pub fn line(&self) -> &'a str {
+        self.line
+    }
+
+    pub fn error(&self, token: &str, kind: ParseErrorKind) -> ParseError {
+        ParseError::new(self.file, self.line_no, self.section, token, kind)
+    }
+
+    /// The next field, which has to be present.
+    pub fn next<T: FromStr>(&mut self, field: &'static str) -> Result<T, ParseError> {
+        match self.optional(field)? {
+            Some(v) => Ok(v),
+            None => Err(self.error(self.line, ParseErrorKind::Missing(field))),
+        }
+    }
+
+    /// The next field, which has to be present and can not be negative.
+    pub fn next_uncertainty(&mut self, field: &'static str) -> Result<f64, ParseError> {
+        match self.uncertainty(field)? {
+            Some(v) => Ok(v),
+            None => Err(self.error(self.line, ParseErrorKind::Missing(field))),
+        }
+    }
+
+    /// The next field if the line has one, which can not be negative.
+    pub fn uncertainty(&mut self, field: &'static str) -> Result<Option<f64>, ParseError> {
+        let value: Option<f64> = self.optional(field)?;
+        match value {
+            Some(v) if v < 0.0 => Err(self.error(&v.to_string(), ParseErrorKind::Negative(field))),
+            _ => Ok(value),
+        }
+    }
+
+    /// The next field if the line has one.
+    pub fn optional<T: FromStr>(&mut self, field: &'static str) -> Result<Option<T>, ParseError> {
+        match self.parts.next() {
+            Some(token) => token
+                .parse()
+                .map(Some)
+                .map_err(|_| self.error(token, ParseErrorKind::Invalid(field))),
+            None => Ok(None),
+        }
+    }
+}
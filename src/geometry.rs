@@ -0,0 +1,73 @@
+use crate::efficiency::{EfficiencyModel, Interpolation, TotalEfficiency, read_efficiency};
+use crate::error::{LineParser, ParseError, ParseErrorKind};
+/// This module describes extended sources as a set of weighted voxels, each
+/// with its own peak and total efficiency.
+use std::fs;
+use std::path::Path;
+
+/// A part of the source, with the share `weight` of its activity. `name`
+/// identifies it in messages.
+#[derive(Debug, Clone)]
+pub struct Voxel {
+    pub name: String,
+    pub weight: f64,
+    pub peak: EfficiencyModel,
+    pub total: TotalEfficiency,
+}
+
+impl Voxel {
+    /// A point source.
+    pub fn point(peak: &EfficiencyModel, total: &TotalEfficiency) -> Self {
+        Self {
+            name: "point source".to_string(),
+            weight: 1.0,
+            peak: peak.clone(),
+            total: total.clone(),
+        }
+    }
+}
+
+/// Read a voxel file with lines `weight peak_file total_file`. Lines starting
+/// with `#` are comments and relative paths are relative to the voxel file.
+/// The weights need not be normalized, and each voxel is named after its line.
+pub fn read_voxels(
+    file_path: &str,
+    peak_interp: Option<Interpolation>,
+    total_interp: Option<Interpolation>,
+) -> Result<Vec<Voxel>, ParseError> {
+    let file_content = fs::read_to_string(file_path).map_err(|e| ParseError::read(file_path, e))?;
+    let dir = Path::new(file_path).parent().unwrap_or(Path::new(""));
+    let resolve = |name: String| dir.join(name).to_string_lossy().into_owned();
+
+    let mut voxels = Vec::new();
+    for (i, line) in file_content.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let mut p = LineParser::new(file_path, i + 1, "", trimmed);
+        let weight: f64 = p.next("voxel weight")?;
+        if weight < 0.0 {
+            return Err(p.error(trimmed, ParseErrorKind::Invalid("voxel weight")));
+        }
+        let peak_file = resolve(p.next("peak efficiency file")?);
+        let total_file = resolve(p.next("total efficiency file")?);
+        voxels.push(Voxel {
+            name: format!("voxel {} ({peak_file}, {total_file})", voxels.len()),
+            weight,
+            peak: read_efficiency(&peak_file, peak_interp)?,
+            total: read_efficiency(&total_file, total_interp)?.into(),
+        });
+    }
+    let total_weight: f64 = voxels.iter().map(|v| v.weight).sum();
+    if total_weight <= 0.0 {
+        return Err(ParseError::new(
+            file_path,
+            0,
+            "",
+            &total_weight.to_string(),
+            ParseErrorKind::ZeroWeight,
+        ));
+    }
+    Ok(voxels)
+}
@@ -1,8 +1,19 @@
+use crate::error::SampleError;
 use rand::prelude::*;
 use rand_distr::{Distribution, Normal};
-use rgsl::MatrixF64;
-use statistical::{mean, standard_deviation};
+use std::fmt;
+use std::str::FromStr;
 
+/// Energy of an annihilation photon in keV.
+pub const ELECTRON_MASS: f64 = 510.99895;
+
+/// How often the level energies of one sample are redrawn before giving up.
+const MAX_ENERGY_REDRAWS: usize = 1000;
+
+/// A nuclear level, `idx` is its position in the Energy-Levels section with
+/// the ground state at zero. The half-life, in seconds, only matters for
+/// levels that live about as long as the coincidence window, and the spin
+/// for angular correlations.
 #[derive(Debug, Clone)]
 pub struct Level {
     pub idx: usize,
@@ -10,33 +21,163 @@ pub struct Level {
     pub denergy: f64,
     pub feeding: f64,
     pub dfeeding: f64,
+    pub half_life: Option<(f64, f64)>,
+    pub spin: Option<f64>,
 }
-#[derive(Debug)]
+/// A transition from level `from` to level `to` with branching ratio `val`
+/// and total internal conversion coefficient `alpha`.
+#[derive(Debug, Clone)]
 pub struct Branch {
     pub from: usize,
     pub to: usize,
     pub val: f64,
     pub dval: f64,
+    pub alpha: f64,
+    pub dalpha: f64,
+    pub multipolarity: Option<Multipolarity>,
 }
 
+/// The lowest multipole order L of a gamma ray and its mixing ratio δ with
+/// L + 1, in the convention of Krane and Steffen.
+#[derive(Debug, Clone, Copy)]
+pub struct Multipolarity {
+    pub order: u32,
+    pub mixing: f64,
+    pub dmixing: f64,
+}
+
+/// A measured peak area for the transition from `from` to `to`, and the
+/// emission probability per decay of the gamma ray with its uncertainty if it
+/// is known independently of the level scheme.
 #[derive(Debug, Clone)]
 pub struct Observation {
     pub from: usize,
     pub to: usize,
     pub counts: f64,
     pub dcounts: f64,
-    pub correction_samples: Vec<f64>,
+    pub emission: Option<(f64, f64)>,
+}
+
+/// What is known about the parent decay and the measurement, if anything.
+/// Values come with their uncertainties, times are in seconds and the
+/// activity, at the start of the measurement, in Bq.
+#[derive(Debug, Clone, Default)]
+pub struct Decay {
+    /// Number of decays during the measurement, which takes precedence over
+    /// the one that follows from the activity.
+    pub decays: Option<(f64, f64)>,
+    pub activity: Option<(f64, f64)>,
+    pub half_life: Option<(f64, f64)>,
+    pub live_time: Option<f64>,
+    /// Defaults to the live time.
+    pub real_time: Option<f64>,
+    /// Fraction of the parent decays that feed this level scheme, one if not given.
+    pub branching: Option<(f64, f64)>,
+}
+
+/// An atomic shell of the daughter that can be left with a vacancy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    K,
+    L,
+}
+
+/// An X-ray line of the daughter, emitted `intensity` times per vacancy in
+/// `shell`, which is the fluorescence yield times the share of this line.
+#[derive(Debug, Clone)]
+pub struct XRay {
+    pub shell: Shell,
+    pub energy: f64,
+    pub intensity: f64,
+    pub dintensity: f64,
+}
+
+/// The part of the feeding of `level` that is by electron capture, and the
+/// probability of capture from each shell.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    pub level: usize,
+    pub fraction: f64,
+    pub k: f64,
+    pub l: f64,
 }
 
-fn truncated_normal(mu: f64, std: f64, r: &mut ThreadRng) -> f64 {
-    if mu == 0.0 {
-        return 0.0;
+/// The fraction of the conversions of the transition from `from` to `to`
+/// that happen in each shell.
+#[derive(Debug, Clone)]
+pub struct ConversionShells {
+    pub from: usize,
+    pub to: usize,
+    pub k: f64,
+    pub l: f64,
+}
+
+/// The atomic vacancies of the cascade and the X-rays that fill them.
+#[derive(Debug, Clone, Default)]
+pub struct Atomic {
+    pub xrays: Vec<XRay>,
+    pub captures: Vec<Capture>,
+    pub conversions: Vec<ConversionShells>,
+}
+
+/// The part of the feeding of `level` that is by β⁺ decay.
+#[derive(Debug, Clone)]
+pub struct PositronFeeding {
+    pub level: usize,
+    pub fraction: f64,
+    pub dfraction: f64,
+}
+
+/// The internal pair formation coefficient of the transition from `from` to
+/// `to`, which is a part of its total conversion coefficient.
+#[derive(Debug, Clone)]
+pub struct PairFormation {
+    pub from: usize,
+    pub to: usize,
+    pub coefficient: f64,
+    pub dcoefficient: f64,
+}
+
+/// The positrons of the cascade, each of which gives two 511 keV photons.
+#[derive(Debug, Clone, Default)]
+pub struct Annihilation {
+    pub feedings: Vec<PositronFeeding>,
+    pub pairs: Vec<PairFormation>,
+}
+
+/// The levels and branches that define the cascade, its X-rays and its
+/// annihilation photons.
+#[derive(Debug, Clone)]
+pub struct LevelScheme {
+    pub levels: Vec<Level>,
+    pub branches: Vec<Branch>,
+    pub atomic: Atomic,
+    pub annihilation: Annihilation,
+}
+
+/// Draw from a normal distribution truncated at zero. A value without an
+/// uncertainty is returned as it is, otherwise it has to be positive, since
+/// rejection sampling would never end for a negative one.
+pub(crate) fn truncated_normal<R: Rng + ?Sized>(
+    mu: f64,
+    std: f64,
+    r: &mut R,
+) -> Result<f64, SampleError> {
+    let error = || SampleError { value: mu, std };
+    if std < 0.0 || std.is_nan() {
+        return Err(error());
+    }
+    if mu == 0.0 || std == 0.0 {
+        return Ok(mu);
+    }
+    if mu < 0.0 {
+        return Err(error());
     }
-    let norm = Normal::new(mu, std).unwrap();
+    let norm = Normal::new(mu, std).map_err(|_| error())?;
     loop {
         let s: f64 = norm.sample(r);
         if s > 0.0 {
-            break s;
+            break Ok(s);
         }
     }
 }
@@ -49,73 +190,314 @@ impl Level {
             denergy,
             feeding,
             dfeeding,
+            half_life: None,
+            spin: None,
         }
     }
 
-    pub fn sample(&self, r: &mut ThreadRng) -> Self {
+    pub fn sample<R: Rng + ?Sized>(&self, r: &mut R) -> Result<Self, SampleError> {
         let idx = self.idx;
-        let energy = self.energy;
-        let denergy = self.denergy;
-        let feeding = truncated_normal(self.feeding, self.dfeeding, r);
+        let energy = truncated_normal(self.energy, self.denergy, r)?;
+        let denergy = 0.0;
+        let feeding = truncated_normal(self.feeding, self.dfeeding, r)?;
         let dfeeding = 0.0;
+        let half_life = match self.half_life {
+            Some((t, dt)) => Some((truncated_normal(t, dt, r)?, 0.0)),
+            None => None,
+        };
 
-        Self {
+        Ok(Self {
             idx,
             energy,
             denergy,
             feeding,
             dfeeding,
-        }
+            half_life,
+            spin: self.spin,
+        })
     }
 }
 
 impl Branch {
-    pub fn new(from: usize, to: usize, val: f64, dval: f64) -> Self {
+    pub fn new(from: usize, to: usize, val: f64, dval: f64, alpha: f64, dalpha: f64) -> Self {
         Self {
             from,
             to,
             val,
             dval,
+            alpha,
+            dalpha,
+            multipolarity: None,
         }
     }
-    pub fn sample(&self, r: &mut ThreadRng) -> Self {
+    pub fn sample<R: Rng + ?Sized>(&self, r: &mut R) -> Result<Self, SampleError> {
         let from = self.from;
         let to = self.to;
-        let val = truncated_normal(self.val, self.dval, r);
+        let val = truncated_normal(self.val, self.dval, r)?;
         let dval = 0.0;
+        let alpha = truncated_normal(self.alpha, self.dalpha, r)?;
+        let dalpha = 0.0;
+        // Mixing ratios can have either sign, so they are not truncated.
+        let multipolarity = self.multipolarity.map(|m| match m.dmixing {
+            d if d > 0.0 => Multipolarity {
+                mixing: Normal::new(m.mixing, d).unwrap().sample(r),
+                dmixing: 0.0,
+                ..m
+            },
+            _ => m,
+        });
 
-        Self {
+        Ok(Self {
             from,
             to,
             val,
             dval,
-        }
+            alpha,
+            dalpha,
+            multipolarity,
+        })
     }
 }
 
 impl Observation {
-    pub fn new(from: usize, to: usize, counts: f64, dcounts: f64, n_samples: usize) -> Self {
+    pub fn new(from: usize, to: usize, counts: f64, dcounts: f64) -> Self {
         Self {
             from,
             to,
             counts,
             dcounts,
-            correction_samples: vec![0.0; n_samples],
+            emission: None,
         }
     }
+}
 
-    pub fn add_correction(&mut self, idx: usize, m: &MatrixF64) {
-        self.correction_samples[idx] = m.get(self.from, self.to);
+impl Decay {
+    /// Draw the activity, half-life, number of decays and branching from their uncertainties.
+    pub fn sample<R: Rng + ?Sized>(&self, r: &mut R) -> Result<Self, SampleError> {
+        let draw = |v: Option<(f64, f64)>, r: &mut R| match v {
+            Some((x, dx)) => truncated_normal(x, dx, r).map(|x| Some((x, 0.0))),
+            None => Ok(None),
+        };
+        Ok(Self {
+            decays: draw(self.decays, r)?,
+            activity: draw(self.activity, r)?,
+            half_life: draw(self.half_life, r)?,
+            live_time: self.live_time,
+            real_time: self.real_time,
+            branching: draw(self.branching, r)?,
+        })
     }
 
-    pub fn corrected_value(&mut self) -> Result<(f64, f64), ()> {
-        let c = mean(&self.correction_samples);
-        if c.is_nan() {
-            return Err(());
+    pub fn branching(&self) -> f64 {
+        self.branching.map_or(1.0, |(b, _)| b)
+    }
+
+    /// Decays during the measurement per Bq of activity at its start: the live
+    /// time, corrected for the decay of the source over the real time.
+    pub fn decays_per_activity(&self) -> Option<f64> {
+        let live = self.live_time?;
+        let real = self.real_time.unwrap_or(live);
+        Some(match self.half_life {
+            Some((t, _)) if t > 0.0 => {
+                let lambda = std::f64::consts::LN_2 / t;
+                live / real * (1.0 - (-lambda * real).exp()) / lambda
+            }
+            _ => live,
+        })
+    }
+
+    /// Number of parent decays during the measurement and its uncertainty,
+    /// which only includes that of the activity if it comes from there.
+    pub fn n_decays(&self) -> Option<(f64, f64)> {
+        match (self.decays, self.activity) {
+            (Some(n), _) => Some(n),
+            (None, Some((a, da))) => {
+                let per_activity = self.decays_per_activity()?;
+                Some((a * per_activity, da * per_activity))
+            }
+            (None, None) => None,
+        }
+    }
+
+    /// Whether anything can be predicted, either the counts or the activity.
+    pub fn is_known(&self) -> bool {
+        self.n_decays().is_some() || self.decays_per_activity().is_some()
+    }
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Shell::K => write!(f, "K"),
+            Shell::L => write!(f, "L"),
+        }
+    }
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "K" | "k" => Ok(Shell::K),
+            "L" | "l" => Ok(Shell::L),
+            _ => Err(format!("unknown shell `{s}`, expected K or L")),
+        }
+    }
+}
+
+impl Atomic {
+    /// Draw the X-ray intensities from their uncertainties.
+    pub fn sample<R: Rng + ?Sized>(&self, r: &mut R) -> Result<Self, SampleError> {
+        Ok(Self {
+            xrays: self
+                .xrays
+                .iter()
+                .map(|x| {
+                    Ok(XRay {
+                        intensity: truncated_normal(x.intensity, x.dintensity, r)?,
+                        dintensity: 0.0,
+                        ..x.clone()
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+            captures: self.captures.clone(),
+            conversions: self.conversions.clone(),
+        })
+    }
+}
+
+impl Annihilation {
+    pub fn is_empty(&self) -> bool {
+        self.feedings.is_empty() && self.pairs.is_empty()
+    }
+
+    /// Draw the β⁺ fractions and pair formation coefficients from their uncertainties.
+    pub fn sample<R: Rng + ?Sized>(&self, r: &mut R) -> Result<Self, SampleError> {
+        Ok(Self {
+            feedings: self
+                .feedings
+                .iter()
+                .map(|f| {
+                    Ok(PositronFeeding {
+                        fraction: truncated_normal(f.fraction, f.dfraction, r)?,
+                        dfraction: 0.0,
+                        ..f.clone()
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+            pairs: self
+                .pairs
+                .iter()
+                .map(|p| {
+                    Ok(PairFormation {
+                        coefficient: truncated_normal(p.coefficient, p.dcoefficient, r)?,
+                        dcoefficient: 0.0,
+                        ..p.clone()
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl LevelScheme {
+    pub fn new(levels: Vec<Level>, branches: Vec<Branch>) -> Self {
+        Self {
+            levels,
+            branches,
+            atomic: Atomic::default(),
+            annihilation: Annihilation::default(),
         }
-        let dc = standard_deviation(&self.correction_samples, None);
-        let val = self.counts * c;
-        let dval = val * f64::sqrt((dc / c).powi(2) + (self.dcounts / self.counts).powi(2));
-        Ok((val, dval))
+    }
+
+    /// Draw a new level scheme with the feedings and branches sampled from their uncertainties.
+    /// Level energies that put a branch at a non-positive energy, which can happen for
+    /// close levels, are redrawn, so they are sampled given the known level ordering.
+    pub fn sample<R: Rng + ?Sized>(&self, r: &mut R) -> Result<Self, SampleError> {
+        let mut levels = self
+            .levels
+            .iter()
+            .map(|l| l.sample(r))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut redraws = 0;
+        while !self.is_ordered(&levels) {
+            redraws += 1;
+            if redraws > MAX_ENERGY_REDRAWS {
+                // Practically unreachable for a scheme that passed read_input, keep the
+                // nominal energies rather than loop forever.
+                for (l, nominal) in levels.iter_mut().zip(self.levels.iter()) {
+                    l.energy = nominal.energy;
+                }
+                break;
+            }
+            for (l, nominal) in levels.iter_mut().zip(self.levels.iter()) {
+                l.energy = truncated_normal(nominal.energy, nominal.denergy, r)?;
+            }
+        }
+        Ok(Self {
+            levels,
+            branches: self
+                .branches
+                .iter()
+                .map(|b| b.sample(r))
+                .collect::<Result<_, _>>()?,
+            atomic: self.atomic.sample(r)?,
+            annihilation: self.annihilation.sample(r)?,
+        })
+    }
+
+    /// Whether every branch goes down in energy with `levels`.
+    fn is_ordered(&self, levels: &[Level]) -> bool {
+        self.branches
+            .iter()
+            .all(|b| levels[b.from].energy > levels[b.to].energy)
+    }
+
+    /// Whether the B-Values define a transition from `from` to `to`.
+    pub fn has_transition(&self, from: usize, to: usize) -> bool {
+        self.branches.iter().any(|b| b.from == from && b.to == to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_chacha::ChaCha12Rng;
+
+    #[test]
+    fn close_levels_keep_their_order() {
+        // Levels 1 and 2 are closer than their energy uncertainties.
+        let levels = vec![
+            Level::new(0, 0.0, 0.0, 0.0, 0.0),
+            Level::new(1, 1000.0, 2.0, 0.0, 0.0),
+            Level::new(2, 1001.0, 2.0, 1.0, 0.0),
+        ];
+        let branches = vec![
+            Branch::new(1, 0, 1.0, 0.0, 0.0, 0.0),
+            Branch::new(2, 1, 0.5, 0.0, 0.0, 0.0),
+            Branch::new(2, 0, 0.5, 0.0, 0.0, 0.0),
+        ];
+        let scheme = LevelScheme::new(levels, branches);
+        let mut r = ChaCha12Rng::seed_from_u64(1);
+        let mut mean = 0.0;
+        for _ in 0..1000 {
+            let sample = scheme.sample(&mut r).unwrap();
+            let e = sample.levels[2].energy - sample.levels[1].energy;
+            assert!(e > 0.0, "transition 2 -> 1 sampled at {e} keV");
+            mean += e / 1000.0;
+        }
+        // The ordering pushes the difference above its nominal 1 keV.
+        assert!(mean > 1.0, "mean transition energy {mean} keV");
+    }
+
+    #[test]
+    fn truncated_normal_rejects_negative_values() {
+        let mut r = ChaCha12Rng::seed_from_u64(1);
+        assert!(truncated_normal(-1.0, 0.1, &mut r).is_err());
+        assert!(truncated_normal(1.0, -0.1, &mut r).is_err());
+        assert_eq!(truncated_normal(-1.0, 0.0, &mut r).unwrap(), -1.0);
+        assert_eq!(truncated_normal(0.0, 0.1, &mut r).unwrap(), 0.0);
+        assert!(truncated_normal(1.0, 0.1, &mut r).unwrap() > 0.0);
     }
 }
@@ -0,0 +1,56 @@
+//! Correct gamma ray intensities for true coincidence summing using the
+//! formalism of Semkow 1990.
+//!
+//! ```no_run
+//! use sum_correction::{RunOptions, read_efficiency, read_source, run_correction};
+//!
+//! let input = read_source("22Ne.dat")?;
+//! let peak = read_efficiency("peak_eff.dat", None)?;
+//! let total = read_efficiency("tot_eff.dat", None)?.into();
+//! let results = run_correction(
+//!     &input.scheme,
+//!     &input.observations,
+//!     &input.decay,
+//!     &peak,
+//!     &total,
+//!     &RunOptions::default(),
+//! )?;
+//! for r in results.iter().flatten() {
+//!     println!("{} {} ± {}", r.energy, r.corrected, r.dcorrected);
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+pub mod angular;
+pub mod array;
+pub mod calibration;
+pub mod efficiency;
+pub mod error;
+pub mod geometry;
+pub mod level_info;
+pub mod monte_carlo;
+pub mod parametric;
+pub mod read_levels;
+pub mod stats;
+pub mod sum_correction;
+
+pub use angular::{Attenuation, Correlation};
+pub use array::{AddBackGroup, Detector, DetectorArray, read_array};
+pub use calibration::{
+    Calibration, CalibrationError, CalibrationOptions, CalibrationPoint, CalibrationSource,
+    calibrate,
+};
+pub use efficiency::{
+    Efficiency, EfficiencyCurve, EfficiencyEvaluator, EfficiencyModel, Interpolation, OutOfRange,
+    TotalEfficiency, TotalEvaluator, make_efficiency, read_efficiency, transitions_out_of_range,
+};
+pub use error::{EfficiencyRangeError, ParseError, ParseErrorKind, RunError, SampleError};
+pub use geometry::{Voxel, read_voxels};
+pub use level_info::{Branch, Decay, Level, LevelScheme, Observation};
+pub use monte_carlo::{
+    CorrectionResult, CountRate, DecayResult, PredictedPeak, RandomResult, RandomSumming,
+    RunOptions, SpectrumResults, SuppressedResult, UndefinedTransition, run_array_correction,
+    run_correction, run_prediction, run_volume_correction, run_volume_prediction,
+};
+pub use parametric::{ParametricEfficiency, ParametricForm, read_parametric, write_parametric};
+pub use read_levels::{Input, read_input, read_source};
+pub use sum_correction::{Cascade, Contribution, Response};
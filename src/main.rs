@@ -1,14 +1,13 @@
-mod efficiency;
-mod level_info;
-mod read_levels;
-mod sum_correction;
 use clap::Parser;
 use color_eyre::eyre::Result;
 use indicatif::ProgressBar;
-use level_info::Observation;
-use rgsl::MatrixF64;
 use std::fs::File;
 use std::io::{BufWriter, Write};
+use sum_correction::{
+    Attenuation, CalibrationError, CalibrationOptions, CalibrationSource, Contribution,
+    CorrectionResult, CountRate, Decay, EfficiencyModel, Interpolation, LevelScheme, OutOfRange,
+    ParseError, PredictedPeak, RandomSumming, RunOptions, SpectrumResults, TotalEfficiency, Voxel,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None, arg_required_else_help = true)]
@@ -16,20 +15,145 @@ use std::io::{BufWriter, Write};
 /// formalism of Semkov.
 struct Args {
     /// Input file with branching level, branching ratios, and observed values.
-    input: String,
+    #[arg(required_unless_present = "calibrate")]
+    input: Option<String>,
 
-    /// Path to the two column peak efficiency file.
+    /// Calibration source files, each with a Decay section giving the number of decays.
+    /// Instead of correcting an input, fit a summing free peak efficiency to their
+    /// observations and write it to --output, peak_eff.dat by default.
+    #[arg(long, num_args = 1.., conflicts_with = "input")]
+    calibrate: Vec<String>,
+
+    /// Degree of the polynomial in ln(E) fitted to ln(efficiency) with --calibrate.
+    #[arg(long, default_value_t = 3)]
+    calibration_degree: usize,
+
+    /// Path to the peak efficiency file, energy, efficiency and an optional uncertainty,
+    /// or the parameters of a fit if it starts with a Model section.
     #[arg(short, long)]
     peak_eff_file: Option<String>,
 
-    /// Path to the two column total efficiency file.
+    /// Path to the total efficiency file, see --peak-eff-file.
     #[arg(short, long)]
     total_eff_file: Option<String>,
 
+    /// Path to a peak-to-total ratio file used instead of the total efficiency, which
+    /// is then the peak efficiency divided by the ratio. The --total-* options apply to it.
+    #[arg(long, conflicts_with = "total_eff_file")]
+    pt_file: Option<String>,
+
+    /// Interpolation of the peak efficiency: linear, cubic, akima, steffen or loglog.
+    /// Overrides an `# interpolation:` header in the file, the default is cubic.
+    #[arg(long)]
+    peak_interp: Option<Interpolation>,
+
+    /// Interpolation of the total efficiency, see --peak-interp.
+    #[arg(long)]
+    total_interp: Option<Interpolation>,
+
+    /// What to do with transitions outside of the peak efficiency table: error, clamp or extrapolate.
+    #[arg(long, default_value_t = OutOfRange::Error)]
+    peak_out_of_range: OutOfRange,
+
+    /// What to do with transitions outside of the total efficiency table, see --peak-out-of-range.
+    #[arg(long, default_value_t = OutOfRange::Error)]
+    total_out_of_range: OutOfRange,
+
+    /// Relative uncertainty on the overall scale of the peak efficiency.
+    #[arg(long, default_value_t = 0.0, value_parser = parse_non_negative)]
+    peak_eff_scale: f64,
+
+    /// Relative uncertainty on the overall scale of the total efficiency.
+    #[arg(long, default_value_t = 0.0, value_parser = parse_non_negative)]
+    total_eff_scale: f64,
+
+    /// Total efficiency of a 511 keV annihilation photon, used instead of the total
+    /// efficiency curve, e.g. when positrons escape the source before annihilating.
+    #[arg(long, value_parser = parse_non_negative)]
+    annihilation_total_eff: Option<f64>,
+
+    /// Absolute uncertainty of --annihilation-total-eff.
+    #[arg(long, default_value_t = 0.0, requires = "annihilation_total_eff", value_parser = parse_non_negative)]
+    annihilation_total_eff_unc: f64,
+
+    /// Pile-up resolving time in seconds. Adds a random summing correction at the
+    /// --count-rate, or at the rate estimated from the observed peaks and the live time
+    /// of the Decay section.
+    #[arg(long, value_parser = parse_non_negative)]
+    resolving_time: Option<f64>,
+
+    /// Absolute uncertainty of --resolving-time.
+    #[arg(long, default_value_t = 0.0, requires = "resolving_time", value_parser = parse_non_negative)]
+    resolving_time_unc: f64,
+
+    /// Total count rate of the detector in counts per second, for --resolving-time.
+    #[arg(long, requires = "resolving_time", value_parser = parse_non_negative)]
+    count_rate: Option<f64>,
+
+    /// Absolute uncertainty of --count-rate.
+    #[arg(long, default_value_t = 0.0, requires = "count_rate", value_parser = parse_non_negative)]
+    count_rate_unc: f64,
+
+    /// Coincidence resolving time in seconds. Levels with a half-life in the Half-Lives
+    /// section only let the photons before and after them sum if they decay within it.
+    #[arg(long, value_parser = parse_non_negative)]
+    coincidence_window: Option<f64>,
+
+    /// Solid angle attenuation coefficients Q2,Q4 of the detector. Adds γ-γ angular
+    /// correlations for gamma rays with spins and multipolarities in the input.
+    #[arg(long, value_delimiter = ',', num_args = 2)]
+    attenuation: Option<Vec<f64>>,
+
+    /// Voxel file of an extended source, with lines `weight peak_file total_file`, used
+    /// instead of a single peak and total efficiency. The corrections average the
+    /// responses with and without summing over the voxels.
+    #[arg(long, conflicts_with_all = ["peak_eff_file", "total_eff_file", "pt_file", "calibrate"])]
+    voxels: Option<String>,
+
+    /// Array file of a multi-detector setup, with a Detectors section of lines
+    /// `name peak_file total_file` and an optional Add-Back section of lines
+    /// `name peak_file|- member...`. Corrects the spectrum of every detector and
+    /// of every add-back group.
+    #[arg(long, conflicts_with_all = ["peak_eff_file", "total_eff_file", "pt_file", "voxels", "calibrate"])]
+    array: Option<String>,
+
+    /// Path to the efficiency file of an anti-Compton shield, see --peak-eff-file. A photon
+    /// that reaches the shield vetoes the event, so the true coincidence correction of the
+    /// suppressed spectrum is reported as well. Follows --total-interp and --total-out-of-range.
+    #[arg(long, conflicts_with = "calibrate")]
+    veto_eff_file: Option<String>,
+
+    /// Report the summing-out factor L and the summing-in factor G of each observation,
+    /// with this many cascades that contribute the most to each.
+    #[arg(long)]
+    cascades: Option<usize>,
+
+    /// Instead of correcting the observations, predict the counts per decay of every
+    /// peak of the level scheme, including pure sum peaks of cascades between levels
+    /// without a transition, and the expected counts if the Decay section gives them.
+    #[arg(long, conflicts_with_all = ["calibrate", "resolving_time", "veto_eff_file", "cascades", "samples_file", "quantiles"])]
+    predict: bool,
+
     /// Number of Monte-Carlo samples to run.
     #[arg(short, long, default_value_t = 10000)]
     samples: i64,
 
+    /// Number of threads used for sampling, 0 uses every available core.
+    #[arg(short = 'j', long, default_value_t = 0)]
+    threads: usize,
+
+    /// Seed for the random number generator, a random one is drawn and reported if not given.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Quantiles of the correction factor to estimate, e.g. 0.16,0.5,0.84
+    #[arg(short, long, value_delimiter = ',', value_parser = parse_quantile)]
+    quantiles: Vec<f64>,
+
+    /// Keep every Monte Carlo sample and write the correction factors to this csv file.
+    #[arg(long)]
+    samples_file: Option<String>,
+
     /// Output file
     #[arg(short, long)]
     output: Option<String>,
@@ -39,125 +163,846 @@ struct Args {
     human_readable: bool,
 }
 
+/// A probability strictly between 0 and 1 for `--quantiles`.
+fn parse_quantile(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(p) if p > 0.0 && p < 1.0 => Ok(p),
+        Ok(p) => Err(format!("{p} is not between 0 and 1")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// A value or uncertainty that can not be negative, e.g. for `--count-rate-unc`.
+fn parse_non_negative(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(x) if x >= 0.0 => Ok(x),
+        Ok(x) => Err(format!("{x} is negative")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Column names of the csv output, with a leading spectrum column for an array.
+fn csv_header(
+    quantiles: &[f64],
+    decay: bool,
+    random: bool,
+    suppressed: bool,
+    decomposition: bool,
+    array: bool,
+) -> String {
+    let mut header = if array { "spectrum," } else { "" }.to_string();
+    header.push_str("Eg,counts,dcounts,corrected,dcorrected,dcorrected_eff");
+    for p in quantiles.iter() {
+        header.push_str(&format!(",q{p}"));
+    }
+    if decay {
+        header.push_str(concat!(
+            ",expected,dexpected,expected_nosum,dexpected_nosum",
+            ",activity,dactivity,activity_nosum,dactivity_nosum"
+        ));
+    }
+    if random {
+        header.push_str(",rate,drate,random,drandom,coincidence,dcoincidence");
+    }
+    if suppressed {
+        header.push_str(",suppressed,dsuppressed,corrected_suppressed,dcorrected_suppressed");
+    }
+    if decomposition {
+        header.push_str(",summing_out,dsumming_out,summing_in,dsumming_in,losses,gains");
+    }
+    header
+}
+
+/// Whether the results have the decay columns.
+fn has_decay(spectra: &[SpectrumResults]) -> bool {
+    spectra
+        .iter()
+        .flat_map(|s| s.results.iter().flatten())
+        .any(|r| r.decay.is_some())
+}
+
+/// Whether the results have the random summing columns.
+fn has_random(spectra: &[SpectrumResults]) -> bool {
+    spectra
+        .iter()
+        .flat_map(|s| s.results.iter().flatten())
+        .any(|r| r.random.is_some())
+}
+
+/// Whether the results have the suppressed spectrum columns.
+fn has_suppressed(spectra: &[SpectrumResults]) -> bool {
+    spectra
+        .iter()
+        .flat_map(|s| s.results.iter().flatten())
+        .any(|r| r.suppressed.is_some())
+}
+
+/// Whether the results are those of a detector array, which are named.
+fn is_array(spectra: &[SpectrumResults]) -> bool {
+    spectra.iter().any(|s| !s.name.is_empty())
+}
+
+fn csv_header_for(spectra: &[SpectrumResults], quantiles: &[f64], decomposition: bool) -> String {
+    csv_header(
+        quantiles,
+        has_decay(spectra),
+        has_random(spectra),
+        has_suppressed(spectra),
+        decomposition,
+        is_array(spectra),
+    )
+}
+
+/// The csv row of `r`, led by the spectrum name for an array.
+fn csv_row_in(spectrum: &str, r: &CorrectionResult, decomposition: bool) -> String {
+    match spectrum {
+        "" => csv_row(r, decomposition),
+        name => format!("{name},{}", csv_row(r, decomposition)),
+    }
+}
+
+/// The cascades with their shares of S0, `cascade=share` separated by `;`
+/// for csv and `cascade (share %)` separated by `,` for humans.
+fn cascade_list(contributions: &[Contribution], for_humans: bool) -> String {
+    let entries: Vec<String> = contributions
+        .iter()
+        .map(|c| match for_humans {
+            true => format!("{} ({:.3} %)", c.cascade, 100.0 * c.share),
+            false => format!("{}={:.4e}", c.cascade, c.share),
+        })
+        .collect();
+    entries.join(if for_humans { ", " } else { "; " })
+}
+
+fn csv_row(r: &CorrectionResult, decomposition: bool) -> String {
+    let mut row = format!(
+        "{0:.2},{1:.3},{2:.3},{3:.3},{4:.3},{5:.3}",
+        r.energy, r.counts, r.dcounts, r.corrected, r.dcorrected, r.dcorrected_eff
+    );
+    for (_, q) in r.quantiles.iter() {
+        row.push_str(&format!(",{q:.5}"));
+    }
+    if let Some(d) = &r.decay {
+        for v in [
+            d.expected,
+            d.expected_no_summing,
+            d.activity,
+            d.activity_no_summing,
+        ] {
+            let (x, dx) = v.unwrap_or((f64::NAN, f64::NAN));
+            row.push_str(&format!(",{x:.5e},{dx:.5e}"));
+        }
+    }
+    if let Some(rs) = &r.random {
+        row.push_str(&format!(",{:.5e},{:.5e}", rs.rate.0, rs.rate.1));
+        for (x, dx) in [rs.factor, rs.coincidence] {
+            row.push_str(&format!(",{x:.5},{dx:.5}"));
+        }
+    }
+    if let Some(sp) = &r.suppressed {
+        row.push_str(&format!(
+            ",{:.5},{:.5},{:.3},{:.3}",
+            sp.correction.0, sp.correction.1, sp.corrected.0, sp.corrected.1
+        ));
+    }
+    if decomposition {
+        row.push_str(&format!(
+            ",{:.5},{:.5},{:.5},{:.5},{},{}",
+            r.summing_out.0,
+            r.summing_out.1,
+            r.summing_in.0,
+            r.summing_in.1,
+            cascade_list(&r.losses, false),
+            cascade_list(&r.gains, false)
+        ));
+    }
+    row
+}
+
+fn human_row(r: &CorrectionResult, decomposition: bool) -> String {
+    let mut row = format!(
+        "E𝛾 = {0:<10.2} | Observed = {1:<7.1} ± {2:<5.1} | Corrected = {3:<7.1} ± {4:<5.1} (eff. {5:<5.1})",
+        r.energy, r.counts, r.dcounts, r.corrected, r.dcorrected, r.dcorrected_eff
+    );
+    for (p, q) in r.quantiles.iter() {
+        row.push_str(&format!(" | C(q{p}) = {q:<7.4}"));
+    }
+    if let Some(d) = &r.decay {
+        let pairs = [
+            ("Expected", d.expected, d.expected_no_summing),
+            ("Activity", d.activity, d.activity_no_summing),
+        ];
+        for (name, with, without) in pairs {
+            if let (Some((x, dx)), Some((x0, dx0))) = (with, without) {
+                row.push_str(&format!(
+                    " | {name} = {x:.4e} ± {dx:.2e} (no summing {x0:.4e} ± {dx0:.2e})"
+                ));
+            }
+        }
+    }
+    if let Some(rs) = &r.random {
+        row.push_str(&format!(
+            " | C(random) = {:.5} ± {:.5} at {:.4e} cps | C(coincidence) = {:.5} ± {:.5}",
+            rs.factor.0, rs.factor.1, rs.rate.0, rs.coincidence.0, rs.coincidence.1
+        ));
+    }
+    if let Some(sp) = &r.suppressed {
+        row.push_str(&format!(
+            " | C(suppressed) = {:.5} ± {:.5} | Corrected (suppressed) = {:<7.1} ± {:<5.1}",
+            sp.correction.0, sp.correction.1, sp.corrected.0, sp.corrected.1
+        ));
+    }
+    if decomposition {
+        row.push_str(&format!(
+            " | L = {:.5} ± {:.5} | G = {:.5} ± {:.5}",
+            r.summing_out.0, r.summing_out.1, r.summing_in.0, r.summing_in.1
+        ));
+        for (name, list) in [("summing out", &r.losses), ("summing in", &r.gains)] {
+            if !list.is_empty() {
+                row.push_str(&format!("\n    {name}: {}", cascade_list(list, true)));
+            }
+        }
+    }
+    row
+}
+
 fn print_function(
-    obs: &mut [Observation],
-    energy_matrix: &MatrixF64,
+    spectra: &[SpectrumResults],
+    provenance: &[String],
+    quantiles: &[f64],
+    decomposition: bool,
     in_file: &str,
     for_humans: bool,
 ) {
-    if for_humans {
-        for o in obs.iter_mut() {
-            match o.corrected_value() {
-                Ok((m, std)) => println!(
-                    "E𝛾 = {0:<10.2} | Observed = {1:<7.1} ± {2:<5.1} | Corrected = {m:<7.1} ± {std:<5.1}",
-                    energy_matrix.get(o.from, o.to),
-                    o.counts,
-                    o.dcounts
-                ),
-                Err(()) => eprintln!(
-                    "Observed transition from {} to {} was not defined in the B-Values section of {}, skipping!",
-                    o.from, o.to, in_file
-                ),
-            };
+    for line in provenance.iter() {
+        println!("# {line}");
+    }
+    if !for_humans {
+        println!("{}", csv_header_for(spectra, quantiles, decomposition));
+    }
+    for spectrum in spectra.iter() {
+        if for_humans && !spectrum.name.is_empty() {
+            println!("Spectrum {}:", spectrum.name);
         }
-    } else {
-        println!("Eg,counts,dcounts,corrected,dcorrected");
-        for o in obs.iter_mut() {
-            match o.corrected_value() {
-                Ok((m, std)) => println!(
-                    "{0:.2},{1:.3},{2:.3},{m:.3},{std:.3}",
-                    energy_matrix.get(o.from, o.to),
-                    o.counts,
-                    o.dcounts
-                ),
-                Err(()) => eprintln!(
-                    "Observed transition from {} to {} was not defined in the B-Values section of {}, skipping!",
-                    o.from, o.to, in_file
-                ),
+        for r in spectrum.results.iter() {
+            match r {
+                Ok(r) if for_humans => println!("{}", human_row(r, decomposition)),
+                Ok(r) => println!("{}", csv_row_in(&spectrum.name, r, decomposition)),
+                Err(e) => eprintln!("{e} of {in_file}, skipping!"),
             };
         }
     }
 }
 
-fn write_output(obs: &mut [Observation], energy_matrix: &MatrixF64, in_file: &str, out_file: &str) {
+fn write_output(
+    spectra: &[SpectrumResults],
+    provenance: &[String],
+    quantiles: &[f64],
+    decomposition: bool,
+    in_file: &str,
+    out_file: &str,
+) {
     let output = File::create(out_file).expect("Failed to create output file!");
     let mut buf_writer = BufWriter::new(output);
-    writeln!(buf_writer, "Eg,counts,dcounts,corrected,dcorrected")
-        .expect("Failed to write csv header!");
-    for o in obs.iter_mut() {
-        match o.corrected_value() {
-            Ok((m, std)) => writeln!(
-                buf_writer,
-                "{0:.2},{1:.3},{2:.3},{m:.3},{std:.3}",
-                energy_matrix.get(o.from, o.to),
-                o.counts,
-                o.dcounts
-            )
-            .expect("Data write failed!"),
-            Err(()) => {
-                eprintln!(
-                    "Observed transition from {} to {} was not defined in the B-Values section of {}, skipping!",
-                    o.from, o.to, in_file
+    for line in provenance.iter() {
+        writeln!(buf_writer, "# {line}").expect("Failed to write provenance header!");
+    }
+    writeln!(
+        buf_writer,
+        "{}",
+        csv_header_for(spectra, quantiles, decomposition)
+    )
+    .expect("Failed to write csv header!");
+    for spectrum in spectra.iter() {
+        for r in spectrum.results.iter() {
+            match r {
+                Ok(r) => writeln!(
+                    buf_writer,
+                    "{}",
+                    csv_row_in(&spectrum.name, r, decomposition)
+                )
+                .expect("Data write failed!"),
+                Err(e) => {
+                    eprintln!("{e} of {in_file}, skipping!");
+                }
+            };
+        }
+    }
+}
+
+/// The predicted peaks of each spectrum, as csv rows after their header or as
+/// lines for humans.
+fn prediction_lines(spectra: &[(String, Vec<PredictedPeak>)], for_humans: bool) -> Vec<String> {
+    let array = spectra.iter().any(|(name, _)| !name.is_empty());
+    let decay = spectra
+        .iter()
+        .flat_map(|(_, peaks)| peaks.iter())
+        .any(|p| p.expected.is_some());
+    let mut lines = Vec::new();
+    if !for_humans {
+        let mut header = if array { "spectrum," } else { "" }.to_string();
+        header.push_str("from,to,Eg,sum_peak,response,dresponse,response_nosum,dresponse_nosum");
+        if decay {
+            header.push_str(",expected,dexpected");
+        }
+        lines.push(header);
+    }
+    for (name, peaks) in spectra.iter() {
+        if for_humans && !name.is_empty() {
+            lines.push(format!("Spectrum {name}:"));
+        }
+        for p in peaks.iter() {
+            let line = if for_humans {
+                let mut line = format!(
+                    "E𝛾 = {:<10.2} | {} -> {}{} | S = {:.4e} ± {:.2e} | S0 = {:.4e} ± {:.2e}",
+                    p.energy,
+                    p.from,
+                    p.to,
+                    if p.sum_peak { " (sum peak)" } else { "" },
+                    p.response.0,
+                    p.response.1,
+                    p.response_no_summing.0,
+                    p.response_no_summing.1
                 );
+                if let Some((x, dx)) = p.expected {
+                    line.push_str(&format!(" | Expected = {x:.4e} ± {dx:.2e}"));
+                }
+                line
+            } else {
+                let mut line = if array {
+                    format!("{name},")
+                } else {
+                    String::new()
+                };
+                line.push_str(&format!(
+                    "{},{},{:.2},{},{:.5e},{:.5e},{:.5e},{:.5e}",
+                    p.from,
+                    p.to,
+                    p.energy,
+                    p.sum_peak,
+                    p.response.0,
+                    p.response.1,
+                    p.response_no_summing.0,
+                    p.response_no_summing.1
+                ));
+                if decay {
+                    let (x, dx) = p.expected.unwrap_or((f64::NAN, f64::NAN));
+                    line.push_str(&format!(",{x:.5e},{dx:.5e}"));
+                }
+                line
+            };
+            lines.push(line);
+        }
+    }
+    lines
+}
+
+/// Every sample of the correction factor, one column per observation, named
+/// `spectrum energy` for an array.
+fn write_samples(spectra: &[SpectrumResults], samples_file: &str) {
+    let output = File::create(samples_file).expect("Failed to create samples file!");
+    let mut buf_writer = BufWriter::new(output);
+    let kept: Vec<(String, &Vec<f64>)> = spectra
+        .iter()
+        .flat_map(|sp| {
+            sp.results.iter().flatten().filter_map(move |r| {
+                let name = match sp.name.as_str() {
+                    "" => format!("{:.2}", r.energy),
+                    name => format!("{name} {:.2}", r.energy),
+                };
+                r.samples.as_ref().map(|s| (name, s))
+            })
+        })
+        .collect();
+    let header: Vec<&str> = kept.iter().map(|(name, _)| name.as_str()).collect();
+    writeln!(buf_writer, "{}", header.join(",")).expect("Failed to write samples header!");
+    let n_samples = kept.first().map_or(0, |(_, s)| s.len());
+    for i in 0..n_samples {
+        let row: Vec<String> = kept.iter().map(|(_, s)| format!("{:.6}", s[i])).collect();
+        writeln!(buf_writer, "{}", row.join(",")).expect("Sample write failed!");
+    }
+}
+
+/// List the transitions of the level scheme outside of `curve`. Returns false
+/// if there are any and the curve refuses to handle them.
+fn check_range(
+    scheme: &LevelScheme,
+    model: &EfficiencyModel,
+    name: &str,
+    xrays: bool,
+    annihilation: bool,
+) -> bool {
+    let outside = sum_correction::transitions_out_of_range(scheme, model, xrays, annihilation);
+    if outside.is_empty() {
+        return true;
+    }
+    let (lo, hi) = model.range();
+    let refuse = model.out_of_range() == OutOfRange::Error;
+    eprintln!(
+        "{}: {} photon(s) outside of the {name} efficiency range {lo} to {hi} keV{}",
+        if refuse { "Error" } else { "Warning" },
+        outside.len(),
+        if refuse {
+            String::new()
+        } else {
+            format!(", using {}", model.out_of_range())
+        }
+    );
+    for (what, e) in outside.iter() {
+        eprintln!("    {what} ({e:.2} keV)");
+    }
+    !refuse
+}
+
+/// The Decay section as given, for the provenance header.
+fn decay_provenance(decay: &Decay) -> String {
+    let mut parts = Vec::new();
+    let pairs = [
+        ("decays", decay.decays),
+        ("activity (Bq)", decay.activity),
+        ("half-life (s)", decay.half_life),
+        ("branching", decay.branching),
+    ];
+    for (name, v) in pairs {
+        if let Some((x, dx)) = v {
+            parts.push(format!("{name} {x} ± {dx}"));
+        }
+    }
+    for (name, v) in [
+        ("live time (s)", decay.live_time),
+        ("real time (s)", decay.real_time),
+    ] {
+        if let Some(t) = v {
+            parts.push(format!("{name} {t}"));
+        }
+    }
+    format!("decay: {}", parts.join(", "))
+}
+
+/// Fit the peak efficiency to the calibration sources and write it to `out_file`.
+fn run_calibration(
+    source_files: &[String],
+    total_eff: &TotalEfficiency,
+    mut provenance: Vec<String>,
+    options: &CalibrationOptions,
+    out_file: &str,
+) {
+    let sources: Vec<CalibrationSource> = source_files
+        .iter()
+        .map(|file| {
+            let input = or_exit(sum_correction::read_source(file));
+            CalibrationSource {
+                name: file.clone(),
+                scheme: input.scheme,
+                observations: input.observations,
+                decay: input.decay,
             }
-        };
+        })
+        .collect();
+    let mut total_ok = true;
+    for source in sources.iter() {
+        total_ok &= check_range(
+            &source.scheme,
+            total_eff.model(),
+            total_eff.name(),
+            true,
+            options.annihilation_eff.is_none(),
+        );
+    }
+    if !total_ok {
+        std::process::exit(1);
     }
+
+    let calibration = match sum_correction::calibrate(&sources, total_eff, options) {
+        Ok(calibration) => calibration,
+        Err(CalibrationError::NotConverged(calibration)) => {
+            eprintln!(
+                "Warning: the corrections did not converge in {} iterations, writing the last fit",
+                calibration.iterations
+            );
+            *calibration
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(1)
+        }
+    };
+
+    provenance.push(format!("sources: {}", source_files.join(", ")));
+    provenance.push(format!(
+        "degree: {}, iterations: {}, chi2/ndf: {:.3} / {}",
+        options.degree, calibration.iterations, calibration.chi2, calibration.ndf
+    ));
+    provenance.push("source,Eg,emission,correction,efficiency,defficiency,fit".to_string());
+    let fitted = calibration.model.function();
+    for p in calibration.points.iter() {
+        provenance.push(format!(
+            "{},{:.2},{:.5e},{:.5},{:.5e},{:.5e},{:.5e}",
+            source_files[p.source],
+            p.energy,
+            p.emission,
+            p.correction,
+            p.efficiency,
+            p.defficiency,
+            fitted.eval(p.energy).unwrap_or(f64::NAN)
+        ));
+    }
+    for line in provenance.iter() {
+        println!("# {line}");
+    }
+    sum_correction::write_parametric(&calibration.model, &provenance, out_file)
+        .expect("Failed to write the efficiency file!");
+}
+
+/// The total efficiency, or the peak-to-total ratio, of the command line and
+/// its line of the provenance header.
+fn load_total(args: &Args) -> (TotalEfficiency, String) {
+    let total_file = match (&args.pt_file, &args.total_eff_file) {
+        (Some(p), _) => p.clone(),
+        (None, Some(p)) => p.clone(),
+        (None, None) => "tot_eff.dat".to_string(),
+    };
+    let total_model = or_exit(sum_correction::read_efficiency(
+        &total_file,
+        args.total_interp,
+    ));
+    let mut total_eff = match args.pt_file {
+        Some(_) => TotalEfficiency::PeakToTotal(total_model),
+        None => TotalEfficiency::Direct(total_model),
+    };
+    total_eff.model_mut().set_scale_unc(args.total_eff_scale);
+    total_eff
+        .model_mut()
+        .set_out_of_range(args.total_out_of_range);
+    let provenance = format!(
+        "total efficiency: {total_file} ({total_eff}, out of range {}, scale uncertainty {})",
+        total_eff.model().out_of_range(),
+        args.total_eff_scale
+    );
+    (total_eff, provenance)
+}
+
+/// Input errors are the user's to fix, so report them without a backtrace.
+fn or_exit<T>(r: Result<T, ParseError>) -> T {
+    r.unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1)
+    })
 }
 
 fn main() -> Result<()> {
     // Better panic messages.
     color_eyre::install()?;
 
-    let mut r = rand::rng();
     let args = Args::parse();
 
-    let in_file = args.input;
-
-    let peak_file = match args.peak_eff_file {
-        Some(p) => p,
-        None => "peak_eff.dat".to_string(),
-    };
+    let name_version = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    let mut provenance = vec![name_version];
+    let attenuation = args
+        .attenuation
+        .as_ref()
+        .map(|q| Attenuation { q2: q[0], q4: q[1] });
+    if let Some(q) = attenuation {
+        provenance.push(format!("attenuation: Q2 {}, Q4 {}", q.q2, q.q4));
+    }
+    if let Some(window) = args.coincidence_window {
+        provenance.push(format!("coincidence window: {window} s"));
+    }
+    if let Some(eff) = args.annihilation_total_eff {
+        provenance.push(format!(
+            "annihilation total efficiency: {eff} ± {}",
+            args.annihilation_total_eff_unc
+        ));
+    }
 
-    let total_file = match args.total_eff_file {
-        Some(p) => p,
-        None => "tot_eff.dat".to_string(),
-    };
+    if !args.calibrate.is_empty() {
+        let out_file = args
+            .output
+            .clone()
+            .unwrap_or_else(|| "peak_eff.dat".to_string());
+        let options = CalibrationOptions {
+            degree: args.calibration_degree,
+            annihilation_eff: args.annihilation_total_eff,
+            coincidence_window: args.coincidence_window,
+            attenuation,
+            ..Default::default()
+        };
+        let (total_eff, total_provenance) = load_total(&args);
+        provenance.insert(1, total_provenance);
+        run_calibration(&args.calibrate, &total_eff, provenance, &options, &out_file);
+        return Ok(());
+    }
 
+    let in_file = args
+        .input
+        .clone()
+        .expect("clap requires an input without --calibrate");
     let n_samples = args.samples as usize;
 
-    let bar = ProgressBar::new(n_samples as u64);
+    let input = or_exit(sum_correction::read_source(&in_file));
+    let (scheme, obs, decay) = (input.scheme, input.observations, input.decay);
+    provenance.insert(1, format!("input: {in_file}"));
+    // Each spectrum to correct, with the voxels of the source. Only an array
+    // has several spectra, and only they are named.
+    let spectra: Vec<(String, Vec<Voxel>)> = match (&args.array, &args.voxels) {
+        (Some(array_file), _) => {
+            let array = or_exit(sum_correction::read_array(
+                array_file,
+                args.peak_interp,
+                args.total_interp,
+            ));
+            provenance.insert(
+                2,
+                format!(
+                    "array: {array_file} ({} detectors, {} add-back groups, out of range peak {} total {}, scale uncertainty peak {} total {})",
+                    array.detectors.len(),
+                    array.groups.len(),
+                    args.peak_out_of_range,
+                    args.total_out_of_range,
+                    args.peak_eff_scale,
+                    args.total_eff_scale
+                ),
+            );
+            array
+                .spectra()
+                .into_iter()
+                .map(|(name, mut peak, mut total)| {
+                    peak.set_scale_unc(args.peak_eff_scale);
+                    peak.set_out_of_range(args.peak_out_of_range);
+                    total.model_mut().set_scale_unc(args.total_eff_scale);
+                    total.model_mut().set_out_of_range(args.total_out_of_range);
+                    (name, vec![Voxel::point(&peak, &total)])
+                })
+                .collect()
+        }
+        (None, Some(voxel_file)) => {
+            let mut voxels = or_exit(sum_correction::read_voxels(
+                voxel_file,
+                args.peak_interp,
+                args.total_interp,
+            ));
+            for v in voxels.iter_mut() {
+                v.peak.set_scale_unc(args.peak_eff_scale);
+                v.peak.set_out_of_range(args.peak_out_of_range);
+                v.total.model_mut().set_scale_unc(args.total_eff_scale);
+                v.total
+                    .model_mut()
+                    .set_out_of_range(args.total_out_of_range);
+            }
+            provenance.insert(
+                2,
+                format!(
+                    "voxels: {voxel_file} ({} voxels, out of range peak {} total {}, scale uncertainty peak {} total {})",
+                    voxels.len(),
+                    args.peak_out_of_range,
+                    args.total_out_of_range,
+                    args.peak_eff_scale,
+                    args.total_eff_scale
+                ),
+            );
+            vec![(String::new(), voxels)]
+        }
+        (None, None) => {
+            let (total_eff, total_provenance) = load_total(&args);
+            let peak_file = args
+                .peak_eff_file
+                .clone()
+                .unwrap_or_else(|| "peak_eff.dat".to_string());
+            let mut peak_eff = or_exit(sum_correction::read_efficiency(
+                &peak_file,
+                args.peak_interp,
+            ));
+            peak_eff.set_scale_unc(args.peak_eff_scale);
+            peak_eff.set_out_of_range(args.peak_out_of_range);
+            provenance.insert(
+                2,
+                format!(
+                    "peak efficiency: {peak_file} ({peak_eff}, out of range {}, scale uncertainty {})",
+                    peak_eff.out_of_range(),
+                    args.peak_eff_scale
+                ),
+            );
+            provenance.insert(3, total_provenance);
+            vec![(String::new(), vec![Voxel::point(&peak_eff, &total_eff)])]
+        }
+    };
 
-    let (levels, branches, mut obs) = read_levels::read_input(&in_file, n_samples);
-    let mut peak_eff_spline = efficiency::make_efficiency(&peak_file);
-    let mut total_eff_spline = efficiency::make_efficiency(&total_file);
+    let veto = args.veto_eff_file.as_ref().map(|veto_file| {
+        let mut veto = or_exit(sum_correction::read_efficiency(
+            veto_file,
+            args.total_interp,
+        ));
+        veto.set_out_of_range(args.total_out_of_range);
+        provenance.push(format!(
+            "veto efficiency: {veto_file} ({veto}, out of range {})",
+            veto.out_of_range()
+        ));
+        veto
+    });
 
-    for i in 0..n_samples {
-        bar.inc(1);
-        let temp_level: Vec<level_info::Level> = levels.iter().map(|l| l.sample(&mut r)).collect();
-        let temp_branch: Vec<level_info::Branch> =
-            branches.iter().map(|b| b.sample(&mut r)).collect();
-
-        let (x, f) = sum_correction::make_x_and_f_matrix(&temp_branch, &temp_level);
-        let energy_matrix = sum_correction::make_transition_energies(&temp_branch, &temp_level);
-        let (peak_matrix, total_matrix) = sum_correction::make_eff_matrix(
-            &energy_matrix,
-            &mut peak_eff_spline,
-            &mut total_eff_spline,
-        );
+    // Check every curve before exiting so every problem is listed at once.
+    // X-rays and annihilation photons only need the peak efficiency for a
+    // peak-to-total ratio.
+    let curve_511 = args.annihilation_total_eff.is_none();
+    let mut curves_ok = true;
+    for (spectrum, voxels) in spectra.iter() {
+        for v in voxels.iter() {
+            let name = |curve: &str| match (spectrum.as_str(), &args.voxels) {
+                ("", Some(_)) => format!("{curve} ({})", v.name),
+                ("", None) => curve.to_string(),
+                (spectrum, _) => format!("{curve} ({spectrum})"),
+            };
+            let pt = matches!(v.total, TotalEfficiency::PeakToTotal(_));
+            curves_ok &= check_range(&scheme, &v.peak, &name("peak"), pt, pt && curve_511);
+            curves_ok &= check_range(
+                &scheme,
+                v.total.model(),
+                &name(v.total.name()),
+                true,
+                curve_511,
+            );
+        }
+    }
+    if let Some(veto) = &veto {
+        curves_ok &= check_range(&scheme, veto, "veto", true, true);
+    }
+    if !curves_ok {
+        std::process::exit(1);
+    }
 
-        let correction = sum_correction::calculate_correction(&x, &f, &peak_matrix, &total_matrix);
-        for o in obs.iter_mut() {
-            o.add_correction(i, &correction);
+    let seed = args.seed.unwrap_or_else(rand::random);
+    provenance.push(format!("samples: {n_samples}"));
+    provenance.push(format!("seed: {seed}"));
+    if decay.is_known() {
+        provenance.push(decay_provenance(&decay));
+    }
+    let annihilation_eff = args
+        .annihilation_total_eff
+        .map(|eff| (eff, args.annihilation_total_eff_unc));
+
+    if args.predict {
+        let bar = ProgressBar::new((n_samples * spectra.len()) as u64);
+        let options = RunOptions {
+            n_samples,
+            threads: args.threads,
+            seed,
+            annihilation_eff,
+            coincidence_window: args.coincidence_window,
+            attenuation,
+            progress: Some(bar.clone()),
+            ..Default::default()
+        };
+        let predictions: Result<Vec<(String, Vec<PredictedPeak>)>, _> = spectra
+            .iter()
+            .map(|(name, voxels)| {
+                sum_correction::run_volume_prediction(&scheme, &decay, voxels, &options)
+                    .map(|peaks| (name.clone(), peaks))
+            })
+            .collect();
+        bar.finish();
+        let predictions = predictions.unwrap_or_else(|e| {
+            eprintln!("Error: {e}");
+            std::process::exit(1)
+        });
+        provenance.push("mode: prediction of every peak".to_string());
+        let provenance_lines = provenance.iter().map(|line| format!("# {line}"));
+        let for_humans = args.human_readable && args.output.is_none();
+        let lines: Vec<String> = provenance_lines
+            .chain(prediction_lines(&predictions, for_humans))
+            .collect();
+        match &args.output {
+            Some(out_file) => {
+                let output = File::create(out_file).expect("Failed to create output file!");
+                let mut buf_writer = BufWriter::new(output);
+                for line in lines.iter() {
+                    writeln!(buf_writer, "{line}").expect("Data write failed!");
+                }
+            }
+            None => lines.iter().for_each(|line| println!("{line}")),
         }
+        return Ok(());
     }
-    bar.finish();
 
-    let energy_matrix = sum_correction::make_transition_energies(&branches, &levels);
+    let random_summing = args.resolving_time.map(|tau| {
+        let rate = match (args.count_rate, decay.live_time) {
+            (Some(rate), _) => CountRate::Given(rate, args.count_rate_unc),
+            (None, Some(live_time)) => CountRate::Estimated(live_time),
+            (None, None) => {
+                eprintln!(
+                    "Error: random summing needs --count-rate or a live-time in the Decay section"
+                );
+                std::process::exit(1)
+            }
+        };
+        let rate_provenance = match rate {
+            CountRate::Given(rate, unc) => format!("count rate {rate} ± {unc} cps"),
+            CountRate::Estimated(_) => "count rate estimated from the observations".to_string(),
+        };
+        provenance.push(format!(
+            "random summing: resolving time {tau} ± {} s, {rate_provenance}",
+            args.resolving_time_unc
+        ));
+        RandomSumming {
+            rate,
+            resolving_time: (tau, args.resolving_time_unc),
+        }
+    });
+
+    let bar = ProgressBar::new((n_samples * spectra.len()) as u64);
+    let options = RunOptions {
+        n_samples,
+        threads: args.threads,
+        seed,
+        quantiles: args.quantiles,
+        keep_samples: args.samples_file.is_some(),
+        annihilation_eff,
+        random_summing,
+        coincidence_window: args.coincidence_window,
+        attenuation,
+        veto,
+        top_cascades: args.cascades.unwrap_or(0),
+        progress: Some(bar.clone()),
+    };
+    let results: Result<Vec<SpectrumResults>, _> = spectra
+        .iter()
+        .map(|(name, voxels)| {
+            sum_correction::run_volume_correction(&scheme, &obs, &decay, voxels, &options).map(
+                |results| SpectrumResults {
+                    name: name.clone(),
+                    results,
+                },
+            )
+        })
+        .collect();
+    bar.finish();
+    // Sampled level energies can still wander outside of a curve that the
+    // nominal scheme fits in.
+    let results = results.unwrap_or_else(|e| {
+        eprintln!("Error: {e}");
+        std::process::exit(1)
+    });
 
     if let Some(out_file) = args.output {
-        write_output(&mut obs, &energy_matrix, &in_file, &out_file);
+        write_output(
+            &results,
+            &provenance,
+            &options.quantiles,
+            args.cascades.is_some(),
+            &in_file,
+            &out_file,
+        );
     } else {
-        print_function(&mut obs, &energy_matrix, &in_file, args.human_readable);
+        print_function(
+            &results,
+            &provenance,
+            &options.quantiles,
+            args.cascades.is_some(),
+            &in_file,
+            args.human_readable,
+        );
+    }
+    if let Some(samples_file) = args.samples_file {
+        write_samples(&results, &samples_file);
     }
 
     Ok(())
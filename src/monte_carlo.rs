@@ -0,0 +1,1171 @@
+use crate::{
+    angular::Attenuation,
+    array::DetectorArray,
+    efficiency::{EfficiencyEvaluator, EfficiencyModel, TotalEfficiency, TotalEvaluator},
+    error::{EfficiencyRangeError, RunError, SampleError},
+    geometry::Voxel,
+    level_info::{Decay, LevelScheme, Observation, truncated_normal},
+    stats::{Accumulator, Welford},
+    sum_correction::{self, Cascade, Contribution, SchemeMatrices},
+};
+/// This module runs the Monte Carlo over the level scheme and collects the
+/// correction factors for each observation.
+use indicatif::ProgressBar;
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use rgsl::MatrixF64;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+/// Number of samples a worker thread takes at a time.
+const BLOCK_SIZE: usize = 256;
+
+/// Settings for a correction run.
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// Number of Monte Carlo samples.
+    pub n_samples: usize,
+    /// Number of worker threads, 0 uses every available core.
+    pub threads: usize,
+    /// Seed for the random number generator. Every block of samples gets its
+    /// own ChaCha stream, so the results only depend on the seed and not on
+    /// the number of threads. `Default` draws a fresh seed.
+    pub seed: u64,
+    /// Probabilities of the correction factor quantiles to estimate.
+    pub quantiles: Vec<f64>,
+    /// Keep every sample of the correction factor. Off by default since it
+    /// needs `n_samples` values per observation.
+    pub keep_samples: bool,
+    /// Total efficiency of a 511 keV annihilation photon and its uncertainty,
+    /// in place of the total efficiency curve.
+    pub annihilation_eff: Option<(f64, f64)>,
+    /// Pile-up of unrelated events, on top of the true coincidence summing.
+    pub random_summing: Option<RandomSumming>,
+    /// Coincidence resolving time in seconds. Levels with a half-life only
+    /// let the photons before and after them sum if they decay within it.
+    /// Without it every level decays at once.
+    pub coincidence_window: Option<f64>,
+    /// Solid angle attenuation of the detector for γ-γ angular correlations,
+    /// which are left out without it.
+    pub attenuation: Option<Attenuation>,
+    /// Efficiency of an anti-Compton shield for every voxel. With it the
+    /// correction of the suppressed spectrum is reported as well.
+    pub veto: Option<EfficiencyModel>,
+    /// Number of cascades listed for the summing-out and the summing-in of
+    /// each observation. Zero by default, since finding them costs one extra
+    /// response per transition.
+    pub top_cascades: usize,
+    /// Incremented once per sample if given.
+    pub progress: Option<ProgressBar>,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            n_samples: 10000,
+            threads: 0,
+            seed: rand::random(),
+            quantiles: Vec::new(),
+            keep_samples: false,
+            annihilation_eff: None,
+            random_summing: None,
+            coincidence_window: None,
+            attenuation: None,
+            veto: None,
+            top_cascades: 0,
+            progress: None,
+        }
+    }
+}
+
+/// The total count rate of the detector in counts per second.
+#[derive(Debug, Clone, Copy)]
+pub enum CountRate {
+    /// A measured rate and its uncertainty.
+    Given(f64, f64),
+    /// Estimated in every sample from the observed peaks and the sampled
+    /// efficiencies, over this live time in seconds.
+    Estimated(f64),
+}
+
+/// Random summing: an event of an unrelated decay within the resolving time τ
+/// before or after takes a count out of its peak, so the peaks keep the share
+/// exp(-2Rτ) of their counts at the total rate R.
+#[derive(Debug, Clone, Copy)]
+pub struct RandomSumming {
+    pub rate: CountRate,
+    /// Resolving time and its uncertainty in seconds.
+    pub resolving_time: (f64, f64),
+}
+
+/// The correction for a single observation.
+#[derive(Debug, Clone)]
+pub struct CorrectionResult {
+    pub from: usize,
+    pub to: usize,
+    /// Nominal transition energy.
+    pub energy: f64,
+    pub counts: f64,
+    pub dcounts: f64,
+    /// Mean and standard deviation of the correction factor, including random
+    /// summing if it was requested.
+    pub correction: f64,
+    pub dcorrection: f64,
+    /// The observed counts multiplied by the correction factor.
+    pub corrected: f64,
+    pub dcorrected: f64,
+    /// Standard deviation of the correction factor, and of the corrected value,
+    /// when only the efficiencies are sampled. Zero for exact efficiency curves.
+    pub dcorrection_eff: f64,
+    pub dcorrected_eff: f64,
+    /// (probability, value) of the requested correction factor quantiles.
+    pub quantiles: Vec<(f64, f64)>,
+    /// Every Monte Carlo sample of the correction factor, if they were kept.
+    pub samples: Option<Vec<f64>>,
+    /// Expected counts and activity, if the decay is described.
+    pub decay: Option<DecayResult>,
+    /// The two parts of the correction, if random summing was requested.
+    pub random: Option<RandomResult>,
+    /// The correction of the Compton suppressed spectrum, if a veto
+    /// efficiency was given.
+    pub suppressed: Option<SuppressedResult>,
+    /// Mean and standard deviation of the summing-out factor L, the share of
+    /// the peak that summing out leaves, and of the summing-in factor G, the
+    /// share of S0 that summing in adds. The true coincidence correction is
+    /// 1 / (L + G).
+    pub summing_out: (f64, f64),
+    pub summing_in: (f64, f64),
+    /// The cascades that take the most out of the peak and that add the
+    /// most to it, each with its share of S0, for the nominal level scheme
+    /// and efficiencies.
+    pub losses: Vec<Contribution>,
+    pub gains: Vec<Contribution>,
+}
+
+/// The correction factor of the suppressed spectrum and the observed counts
+/// corrected with it, each as a mean and standard deviation. It is the true
+/// coincidence part only: the veto lowers the count rate, so the random
+/// summing factor of the unsuppressed detector does not apply.
+#[derive(Debug, Clone)]
+pub struct SuppressedResult {
+    pub correction: (f64, f64),
+    pub corrected: (f64, f64),
+}
+
+/// The random summing part of a correction and the true coincidence part,
+/// each as a mean and standard deviation. Their product is the correction.
+#[derive(Debug, Clone)]
+pub struct RandomResult {
+    /// Total count rate in counts per second.
+    pub rate: (f64, f64),
+    /// The random summing factor exp(2Rτ).
+    pub factor: (f64, f64),
+    /// The true coincidence summing factor S0/S.
+    pub coincidence: (f64, f64),
+}
+
+/// The absolute predictions for an observation of a decay source. Each is a
+/// value and its uncertainty, and `None` if the decay description does not
+/// determine it.
+#[derive(Debug, Clone)]
+pub struct DecayResult {
+    /// Counts expected in the peak with summing, and without.
+    pub expected: Option<(f64, f64)>,
+    pub expected_no_summing: Option<(f64, f64)>,
+    /// Activity at the start of the measurement that gives the observed
+    /// counts with summing, and without.
+    pub activity: Option<(f64, f64)>,
+    pub activity_no_summing: Option<(f64, f64)>,
+}
+
+/// The predicted full energy peak of a transition, or a pure sum peak of a
+/// cascade between two levels without a transition between them. Each value
+/// is a mean and standard deviation.
+#[derive(Debug, Clone)]
+pub struct PredictedPeak {
+    pub from: usize,
+    pub to: usize,
+    /// Nominal energy difference of the two levels.
+    pub energy: f64,
+    /// There is no transition `from -> to`, only cascades that sum to it.
+    pub sum_peak: bool,
+    /// Counts in the peak per decay with summing, S, and without, S0, which
+    /// is zero for a sum peak.
+    pub response: (f64, f64),
+    pub response_no_summing: (f64, f64),
+    /// Counts expected in the peak with summing, if the decay describes the
+    /// number of decays.
+    pub expected: Option<(f64, f64)>,
+}
+
+/// What one Monte Carlo sample gives for one predicted peak.
+#[derive(Debug, Clone, Copy)]
+struct PeakSample {
+    response: f64,
+    response_no_summing: f64,
+    expected: Option<f64>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct PeakAccumulator {
+    response: Welford,
+    response_no_summing: Welford,
+    expected: Welford,
+}
+
+impl PeakAccumulator {
+    fn add(&mut self, s: &PeakSample) {
+        self.response.add(s.response);
+        self.response_no_summing.add(s.response_no_summing);
+        if let Some(e) = s.expected {
+            self.expected.add(e);
+        }
+    }
+}
+
+/// An observation refers to a transition that is not in the level scheme.
+#[derive(Debug, Clone)]
+pub struct UndefinedTransition {
+    pub from: usize,
+    pub to: usize,
+}
+
+impl fmt::Display for UndefinedTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Observed transition from {} to {} was not defined in the B-Values section",
+            self.from, self.to
+        )
+    }
+}
+
+impl std::error::Error for UndefinedTransition {}
+
+impl DecayResult {
+    fn new(o: &Observation, decay: &Decay, acc: &DecayAccumulator) -> Self {
+        let moments = |w: &Welford| (w.mean(), w.std());
+        // The activity is the counts times a sampled factor, so the counts
+        // uncertainty is added like for the corrected value.
+        let activity = |w: &Welford| {
+            let (f, df) = moments(w);
+            let a = o.counts * f;
+            (
+                a,
+                a * f64::sqrt((df / f).powi(2) + (o.dcounts / o.counts).powi(2)),
+            )
+        };
+        let has_decays = decay.n_decays().is_some();
+        let has_activity = decay.decays_per_activity().is_some();
+        Self {
+            expected: has_decays.then(|| moments(&acc.expected)),
+            expected_no_summing: has_decays.then(|| moments(&acc.expected_no_summing)),
+            activity: has_activity.then(|| activity(&acc.activity)),
+            activity_no_summing: has_activity.then(|| activity(&acc.activity_no_summing)),
+        }
+    }
+}
+
+impl RandomResult {
+    fn new(acc: &RandomAccumulator) -> Self {
+        let moments = |w: &Welford| (w.mean(), w.std());
+        Self {
+            rate: moments(&acc.rate),
+            factor: moments(&acc.factor),
+            coincidence: moments(&acc.coincidence),
+        }
+    }
+}
+
+impl SuppressedResult {
+    fn new(o: &Observation, acc: &Welford) -> Self {
+        let (c, dc) = (acc.mean(), acc.std());
+        let corrected = o.counts * c;
+        Self {
+            correction: (c, dc),
+            corrected: (
+                corrected,
+                corrected * f64::sqrt((dc / c).powi(2) + (o.dcounts / o.counts).powi(2)),
+            ),
+        }
+    }
+}
+
+impl CorrectionResult {
+    fn new(
+        o: &Observation,
+        energy: f64,
+        acc: ObservationAccumulator,
+        decay: Option<&Decay>,
+        cascades: Cascades,
+    ) -> Self {
+        let c = acc.correction.moments.mean();
+        let dc = acc.correction.moments.std();
+        let corrected = o.counts * c;
+        let dcorrected = corrected * f64::sqrt((dc / c).powi(2) + (o.dcounts / o.counts).powi(2));
+        let dc_eff = if acc.eff_only.count() > 0 {
+            acc.eff_only.std()
+        } else {
+            0.0
+        };
+        Self {
+            from: o.from,
+            to: o.to,
+            energy,
+            counts: o.counts,
+            dcounts: o.dcounts,
+            correction: c,
+            dcorrection: dc,
+            corrected,
+            dcorrected,
+            dcorrection_eff: dc_eff,
+            dcorrected_eff: corrected * dc_eff / c,
+            quantiles: acc
+                .correction
+                .quantiles
+                .iter()
+                .map(|q| (q.p(), q.value()))
+                .collect(),
+            decay: decay.map(|d| DecayResult::new(o, d, &acc.decay)),
+            random: (acc.random.rate.count() > 0).then(|| RandomResult::new(&acc.random)),
+            suppressed: (acc.suppressed.count() > 0)
+                .then(|| SuppressedResult::new(o, &acc.suppressed)),
+            summing_out: (acc.summing_out.mean(), acc.summing_out.std()),
+            summing_in: (acc.summing_in.mean(), acc.summing_in.std()),
+            losses: cascades.losses,
+            gains: cascades.gains,
+            samples: acc.correction.samples,
+        }
+    }
+}
+
+/// What one Monte Carlo sample gives for one observation.
+#[derive(Debug, Clone, Copy)]
+struct ObservationSample {
+    correction: f64,
+    /// The correction with the nominal level scheme and only the efficiencies sampled.
+    eff_only: Option<f64>,
+    decay: Option<DecaySample>,
+    /// The count rate and the random summing factor, which is included in
+    /// `correction`.
+    random: Option<(f64, f64)>,
+    coincidence: f64,
+    /// The true coincidence correction of the suppressed spectrum.
+    suppressed: Option<f64>,
+    /// The summing-out and summing-in factors L and G.
+    summing: (f64, f64),
+}
+
+/// Expected counts, and activity per observed count, with and without summing.
+/// NaN where the decay description does not determine them.
+#[derive(Debug, Clone, Copy)]
+struct DecaySample {
+    expected: f64,
+    expected_no_summing: f64,
+    activity: f64,
+    activity_no_summing: f64,
+}
+
+impl DecaySample {
+    fn new(decay: &Decay, s: f64, s0: f64) -> Self {
+        let b = decay.branching();
+        let n = decay.n_decays().map_or(f64::NAN, |(n, _)| n) * b;
+        let exposure = decay.decays_per_activity().unwrap_or(f64::NAN) * b;
+        Self {
+            expected: n * s,
+            expected_no_summing: n * s0,
+            activity: 1.0 / (exposure * s),
+            activity_no_summing: 1.0 / (exposure * s0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct DecayAccumulator {
+    expected: Welford,
+    expected_no_summing: Welford,
+    activity: Welford,
+    activity_no_summing: Welford,
+}
+
+#[derive(Debug, Clone, Default)]
+struct RandomAccumulator {
+    rate: Welford,
+    factor: Welford,
+    coincidence: Welford,
+}
+
+#[derive(Debug, Clone)]
+struct ObservationAccumulator {
+    correction: Accumulator,
+    eff_only: Welford,
+    decay: DecayAccumulator,
+    random: RandomAccumulator,
+    suppressed: Welford,
+    summing_out: Welford,
+    summing_in: Welford,
+}
+
+impl ObservationAccumulator {
+    fn add(&mut self, s: &ObservationSample) {
+        self.correction.add(s.correction);
+        if let Some(e) = s.eff_only {
+            self.eff_only.add(e);
+        }
+        if let Some(d) = s.decay {
+            self.decay.expected.add(d.expected);
+            self.decay.expected_no_summing.add(d.expected_no_summing);
+            self.decay.activity.add(d.activity);
+            self.decay.activity_no_summing.add(d.activity_no_summing);
+        }
+        if let Some((rate, factor)) = s.random {
+            self.random.rate.add(rate);
+            self.random.factor.add(factor);
+            self.random.coincidence.add(s.coincidence);
+        }
+        if let Some(c) = s.suppressed {
+            self.suppressed.add(c);
+        }
+        self.summing_out.add(s.summing.0);
+        self.summing_in.add(s.summing.1);
+    }
+}
+
+impl RunOptions {
+    fn n_threads(&self) -> usize {
+        if self.threads == 0 {
+            thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            self.threads
+        }
+    }
+}
+
+/// S, S0 and the summing-in part of S averaged over the voxels.
+struct VolumeResponse {
+    s: MatrixF64,
+    s0: MatrixF64,
+    summing_in: MatrixF64,
+}
+
+impl VolumeResponse {
+    /// The summing-out and summing-in factors, see `Response::factors`.
+    fn factors(&self, j: usize, i: usize) -> (f64, f64) {
+        let (s, s_in, s0) = (
+            self.s.get(j, i),
+            self.summing_in.get(j, i),
+            self.s0.get(j, i),
+        );
+        ((s - s_in) / s0, s_in / s0)
+    }
+}
+
+/// The response averaged over the voxels with the weights of `evaluators`.
+/// Averaging the responses, and not the efficiencies, keeps the correction
+/// S0/S right for a source whose efficiencies vary across it.
+fn volume_response(
+    matrices: &SchemeMatrices,
+    evaluators: &mut [(f64, EfficiencyEvaluator, TotalEvaluator)],
+) -> Result<VolumeResponse, EfficiencyRangeError> {
+    let mut sum: Option<VolumeResponse> = None;
+    for (weight, peak, total) in evaluators.iter_mut() {
+        let mut r = matrices.response(peak, total)?;
+        for m in [&mut r.s, &mut r.s0, &mut r.summing_in] {
+            m.scale(*weight).unwrap();
+        }
+        if let Some(sum) = &mut sum {
+            sum.s.add(&r.s).unwrap();
+            sum.s0.add(&r.s0).unwrap();
+            sum.summing_in.add(&r.summing_in).unwrap();
+        } else {
+            sum = Some(VolumeResponse {
+                s: r.s,
+                s0: r.s0,
+                summing_in: r.summing_in,
+            });
+        }
+    }
+    Ok(sum.expect("at least one voxel"))
+}
+
+/// The cascades listed for one observation.
+#[derive(Default)]
+struct Cascades {
+    losses: Vec<Contribution>,
+    gains: Vec<Contribution>,
+}
+
+/// Add `share` to the contribution of `cascade` in `contributions`.
+fn add_contribution(contributions: &mut Vec<Contribution>, cascade: &Cascade, share: f64) {
+    match contributions.iter_mut().find(|c| c.cascade == *cascade) {
+        Some(c) => c.share += share,
+        None => contributions.push(Contribution {
+            cascade: cascade.clone(),
+            share,
+        }),
+    }
+}
+
+/// `volume_response` of the Compton suppressed spectrum, where the veto
+/// efficiency adds to the total efficiency.
+fn suppressed_response(
+    matrices: &SchemeMatrices,
+    evaluators: &mut [(f64, EfficiencyEvaluator, TotalEvaluator)],
+) -> Result<VolumeResponse, EfficiencyRangeError> {
+    let set = |evaluators: &mut [(f64, EfficiencyEvaluator, TotalEvaluator)], on| {
+        for (_, _, total) in evaluators.iter_mut() {
+            total.suppressed = on;
+        }
+    };
+    set(evaluators, true);
+    let response = volume_response(matrices, evaluators);
+    set(evaluators, false);
+    response
+}
+
+/// The state of one worker thread: its own efficiency evaluators for every
+/// voxel and, when the efficiencies are uncertain, the matrices of the nominal
+/// level scheme.
+struct Sampler<'a> {
+    scheme: &'a LevelScheme,
+    observations: &'a [Observation],
+    decay: Option<&'a Decay>,
+    voxels: &'a [Voxel],
+    /// Normalized weight and evaluators of each voxel.
+    evaluators: Vec<(f64, EfficiencyEvaluator, TotalEvaluator)>,
+    annihilation_eff: Option<(f64, f64)>,
+    random_summing: Option<RandomSumming>,
+    window: Option<f64>,
+    attenuation: Option<Attenuation>,
+    veto: Option<&'a EfficiencyModel>,
+    nominal: Option<SchemeMatrices>,
+}
+
+impl<'a> Sampler<'a> {
+    fn new(
+        scheme: &'a LevelScheme,
+        observations: &'a [Observation],
+        decay: Option<&'a Decay>,
+        voxels: &'a [Voxel],
+        options: &'a RunOptions,
+    ) -> Self {
+        let annihilation_eff = options.annihilation_eff;
+        let uncertain = voxels
+            .iter()
+            .any(|v| v.peak.is_uncertain() || v.total.model().is_uncertain())
+            || annihilation_eff.is_some_and(|(_, unc)| unc > 0.0)
+            || options.veto.as_ref().is_some_and(|v| v.is_uncertain());
+        let norm: f64 = voxels.iter().map(|v| v.weight).sum();
+        let evaluators = voxels
+            .iter()
+            .map(|v| {
+                let mut total = v.total.evaluator();
+                total.annihilation = annihilation_eff.map(|(eff, _)| eff);
+                total.veto = options.veto.as_ref().map(|v| v.evaluator());
+                (v.weight / norm, v.peak.evaluator(), total)
+            })
+            .collect();
+        Self {
+            scheme,
+            observations,
+            decay,
+            voxels,
+            evaluators,
+            annihilation_eff,
+            random_summing: options.random_summing,
+            window: options.coincidence_window,
+            attenuation: options.attenuation,
+            veto: options.veto.as_ref(),
+            nominal: uncertain.then(|| {
+                SchemeMatrices::new(scheme, options.coincidence_window, options.attenuation)
+            }),
+        }
+    }
+
+    /// Draw one sample of the level scheme, the efficiencies and the decay.
+    fn draw(&mut self, r: &mut ChaCha12Rng) -> Result<(LevelScheme, Option<Decay>), SampleError> {
+        let temp = self.scheme.sample(r)?;
+        for (voxel, (_, peak, total)) in self.voxels.iter().zip(self.evaluators.iter_mut()) {
+            if voxel.peak.is_uncertain() {
+                voxel.peak.resample(peak, r)?;
+            }
+            if voxel.total.model().is_uncertain() {
+                voxel.total.resample(total, r)?;
+            }
+        }
+        if let Some((eff, unc)) = self.annihilation_eff.filter(|(_, unc)| *unc > 0.0) {
+            let eff = truncated_normal(eff, unc, r)?;
+            for (_, _, total) in self.evaluators.iter_mut() {
+                total.annihilation = Some(eff);
+            }
+        }
+        // Every voxel sees the same shield, so each gets the same draw.
+        if let Some(veto) = self.veto.filter(|v| v.is_uncertain()) {
+            let start = r.clone();
+            for (_, _, total) in self.evaluators.iter_mut() {
+                *r = start.clone();
+                veto.resample(total.veto.as_mut().expect("veto evaluator"), r)?;
+            }
+        }
+        let decay = self.decay.map(|d| d.sample(r)).transpose()?;
+        Ok((temp, decay))
+    }
+
+    /// Draw one sample of the level scheme and efficiencies and push what it
+    /// gives for each observation onto `out`.
+    fn sample(
+        &mut self,
+        r: &mut ChaCha12Rng,
+        out: &mut Vec<ObservationSample>,
+    ) -> Result<(), RunError> {
+        let (temp, decay) = self.draw(r)?;
+        let matrices = SchemeMatrices::new(&temp, self.window, self.attenuation);
+        let random = self
+            .random_summing
+            .map(|rs| self.sample_random(&rs, &matrices, r))
+            .transpose()?;
+
+        let response = volume_response(&matrices, &mut self.evaluators)?;
+        let suppressed = self
+            .veto
+            .map(|_| suppressed_response(&matrices, &mut self.evaluators))
+            .transpose()?;
+        // The same efficiency draw applied to the nominal level scheme isolates
+        // the part of the spread that comes from the efficiencies.
+        let eff_only = self
+            .nominal
+            .as_ref()
+            .map(|m| volume_response(m, &mut self.evaluators))
+            .transpose()?;
+        out.extend(self.observations.iter().map(|o| {
+            let (s, s0) = (response.s.get(o.from, o.to), response.s0.get(o.from, o.to));
+            // Random summing takes its share of what is left in the peak.
+            let factor = random.map_or(1.0, |(_, factor)| factor);
+            ObservationSample {
+                correction: factor * s0 / s,
+                eff_only: eff_only
+                    .as_ref()
+                    .map(|r| r.s0.get(o.from, o.to) / r.s.get(o.from, o.to)),
+                decay: decay.as_ref().map(|d| DecaySample::new(d, s / factor, s0)),
+                random,
+                coincidence: s0 / s,
+                suppressed: suppressed
+                    .as_ref()
+                    .map(|r| r.s0.get(o.from, o.to) / r.s.get(o.from, o.to)),
+                summing: response.factors(o.from, o.to),
+            }
+        }));
+        Ok(())
+    }
+
+    /// The `top` cascades that take the most out of each observed peak and
+    /// that add the most to it, for the nominal level scheme and efficiencies.
+    /// Their shares are relative to S0 averaged over the voxels.
+    fn cascades(&mut self, top: usize) -> Result<Vec<Cascades>, EfficiencyRangeError> {
+        if top == 0 {
+            return Ok(self
+                .observations
+                .iter()
+                .map(|_| Cascades::default())
+                .collect());
+        }
+        let matrices = SchemeMatrices::new(self.scheme, self.window, self.attenuation);
+        let s0 = volume_response(&matrices, &mut self.evaluators)?.s0;
+        let mut voxel_losses = Vec::new();
+        for (weight, peak, total) in self.evaluators.iter_mut() {
+            voxel_losses.push((*weight, matrices.losses(peak, total)?));
+        }
+        let mut cascades = Vec::new();
+        for o in self.observations.iter() {
+            let (j, i) = (o.from, o.to);
+            let (mut losses, mut gains) = (Vec::new(), Vec::new());
+            if self.scheme.has_transition(j, i) {
+                let s0 = s0.get(j, i);
+                for (weight, voxel) in voxel_losses.iter() {
+                    for (cascade, loss) in voxel.iter() {
+                        add_contribution(&mut losses, cascade, weight * loss.get(j, i) / s0);
+                    }
+                }
+                for (weight, peak, total) in self.evaluators.iter_mut() {
+                    for c in matrices.gains(peak, total, j, i, top)? {
+                        add_contribution(&mut gains, &c.cascade, *weight * c.share / s0);
+                    }
+                }
+            }
+            losses.retain(|c| c.share > 0.0);
+            sum_correction::keep_top(&mut losses, top);
+            sum_correction::keep_top(&mut gains, top);
+            cascades.push(Cascades { losses, gains });
+        }
+        Ok(cascades)
+    }
+
+    /// Every peak of the nominal level scheme: the transitions with a gamma
+    /// ray, and the pairs of levels without a transition between them that
+    /// cascades of gamma rays connect.
+    fn peaks(&mut self) -> Result<Vec<(usize, usize)>, EfficiencyRangeError> {
+        let matrices = SchemeMatrices::new(self.scheme, self.window, self.attenuation);
+        let response = volume_response(&matrices, &mut self.evaluators)?;
+        let n_levels = self.scheme.levels.len();
+        let mut peaks = Vec::new();
+        for j in 0..n_levels {
+            for i in 0..j {
+                let gamma = matrices.c.get(j, i) > 0.0;
+                let sum_peak = matrices.x.get(j, i) == 0.0 && response.s.get(j, i) > 0.0;
+                if gamma || sum_peak {
+                    peaks.push((j, i));
+                }
+            }
+        }
+        Ok(peaks)
+    }
+
+    /// Draw one sample of the level scheme and efficiencies and push the
+    /// response of each of `peaks` onto `out`.
+    fn predict(
+        &mut self,
+        peaks: &[(usize, usize)],
+        r: &mut ChaCha12Rng,
+        out: &mut Vec<PeakSample>,
+    ) -> Result<(), RunError> {
+        let (temp, decay) = self.draw(r)?;
+        let matrices = SchemeMatrices::new(&temp, self.window, self.attenuation);
+        let response = volume_response(&matrices, &mut self.evaluators)?;
+        let n = decay
+            .as_ref()
+            .and_then(|d| d.n_decays().map(|(n, _)| n * d.branching()));
+        out.extend(peaks.iter().map(|&(j, i)| {
+            let s = response.s.get(j, i);
+            PeakSample {
+                response: s,
+                response_no_summing: response.s0.get(j, i),
+                expected: n.map(|n| n * s),
+            }
+        }));
+        Ok(())
+    }
+
+    /// Draw the count rate and resolving time, and return the rate with the
+    /// random summing factor exp(2Rτ).
+    fn sample_random(
+        &mut self,
+        random: &RandomSumming,
+        matrices: &SchemeMatrices,
+        r: &mut ChaCha12Rng,
+    ) -> Result<(f64, f64), RunError> {
+        let rate = match random.rate {
+            CountRate::Given(rate, unc) if unc > 0.0 => truncated_normal(rate, unc, r)?,
+            CountRate::Given(rate, _) => rate,
+            CountRate::Estimated(live_time) => {
+                let mut rate = 0.0;
+                for (weight, peak, total) in self.evaluators.iter_mut() {
+                    rate += *weight
+                        * sum_correction::estimate_count_rate(
+                            self.observations,
+                            &matrices.energies,
+                            live_time,
+                            peak,
+                            total,
+                        )?;
+                }
+                rate
+            }
+        };
+        let (tau, dtau) = random.resolving_time;
+        let tau = if dtau > 0.0 {
+            truncated_normal(tau, dtau, r)?
+        } else {
+            tau
+        };
+        Ok((rate, f64::exp(2.0 * rate * tau)))
+    }
+}
+
+/// Draw `options.n_samples` samples in blocks that are handed out to
+/// `options.threads` workers, each with its own sampler from `new_sampler`.
+/// Every block has its own random stream derived from `options.seed`.
+/// `sample` pushes `width` values per sample, and `add` gets the values of
+/// each sample in the order of the samples.
+fn run_blocks<'a, T: Send>(
+    options: &RunOptions,
+    width: usize,
+    new_sampler: impl Fn() -> Sampler<'a> + Sync,
+    sample: impl Fn(&mut Sampler<'a>, &mut ChaCha12Rng, &mut Vec<T>) -> Result<(), RunError> + Sync,
+    mut add: impl FnMut(&[T]),
+) -> Result<(), RunError> {
+    let n_samples = options.n_samples;
+    let n_blocks = n_samples.div_ceil(BLOCK_SIZE);
+    let mut failed: Option<(usize, RunError)> = None;
+    if width > 0 {
+        let next_block = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+        thread::scope(|s| {
+            let (tx, rx) = mpsc::channel::<(usize, Result<Vec<T>, RunError>)>();
+            for _ in 0..options.n_threads().min(n_blocks) {
+                let tx = tx.clone();
+                let next_block = &next_block;
+                let stop = &stop;
+                let new_sampler = &new_sampler;
+                let sample = &sample;
+                s.spawn(move || {
+                    let mut sampler = new_sampler();
+                    loop {
+                        let block = next_block.fetch_add(1, Ordering::Relaxed);
+                        if block >= n_blocks || stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        let start = block * BLOCK_SIZE;
+                        let end = (start + BLOCK_SIZE).min(n_samples);
+                        let mut r = ChaCha12Rng::seed_from_u64(options.seed);
+                        r.set_stream(block as u64);
+                        let mut values = Vec::with_capacity((end - start) * width);
+                        let result = (start..end)
+                            .try_for_each(|_| sample(&mut sampler, &mut r, &mut values))
+                            .map(|_| values);
+                        if result.is_err() {
+                            stop.store(true, Ordering::Relaxed);
+                        } else if let Some(bar) = &options.progress {
+                            bar.inc((end - start) as u64);
+                        }
+                        tx.send((block, result)).unwrap();
+                    }
+                });
+            }
+            drop(tx);
+
+            // Blocks are added in order so the streaming statistics do not
+            // depend on which thread finished first. Each block holds width
+            // values per sample.
+            let mut pending: BTreeMap<usize, Vec<T>> = BTreeMap::new();
+            let mut next = 0;
+            for (block, values) in rx {
+                let values = match values {
+                    Ok(values) => values,
+                    // Report the error of the earliest block so the message
+                    // does not depend on the thread timing either.
+                    Err(e) => {
+                        if failed.as_ref().is_none_or(|(b, _)| block < *b) {
+                            failed = Some((block, e));
+                        }
+                        continue;
+                    }
+                };
+                pending.insert(block, values);
+                while let Some(values) = pending.remove(&next) {
+                    for row in values.chunks(width) {
+                        add(row);
+                    }
+                    next += 1;
+                }
+            }
+        });
+    }
+    match failed {
+        Some((_, e)) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Sample the level scheme `options.n_samples` times and correct each
+/// observation, in the same order as `observations`. Samples are split into
+/// blocks that are handed out to `options.threads` workers, each with its own
+/// efficiency evaluators. Every block has its own random stream derived from
+/// `options.seed`.
+///
+/// If `decay` describes the number of decays or the measurement time, the
+/// expected counts or the activity are sampled as well. With
+/// `options.random_summing` the correction includes the random summing
+/// factor, which is also reported on its own.
+///
+/// Fails if a sampled transition energy falls outside of an efficiency curve
+/// whose out of range policy is `OutOfRange::Error`.
+pub fn run_correction(
+    scheme: &LevelScheme,
+    observations: &[Observation],
+    decay: &Decay,
+    peak_eff: &EfficiencyModel,
+    total_eff: &TotalEfficiency,
+    options: &RunOptions,
+) -> Result<Vec<Result<CorrectionResult, UndefinedTransition>>, RunError> {
+    let voxels = [Voxel::point(peak_eff, total_eff)];
+    run_volume_correction(scheme, observations, decay, &voxels, options)
+}
+
+/// The results of one spectrum of a detector array.
+#[derive(Debug, Clone)]
+pub struct SpectrumResults {
+    pub name: String,
+    pub results: Vec<Result<CorrectionResult, UndefinedTransition>>,
+}
+
+/// Predict the full energy peak of every gamma ray of `scheme` and every pure
+/// sum peak, ordered by energy, for a point source. See
+/// `run_volume_prediction`.
+pub fn run_prediction(
+    scheme: &LevelScheme,
+    decay: &Decay,
+    peak_eff: &EfficiencyModel,
+    total_eff: &TotalEfficiency,
+    options: &RunOptions,
+) -> Result<Vec<PredictedPeak>, RunError> {
+    let voxels = [Voxel::point(peak_eff, total_eff)];
+    run_volume_prediction(scheme, decay, &voxels, options)
+}
+
+/// Sample the level scheme and efficiencies `options.n_samples` times like
+/// `run_volume_correction` and predict the counts per decay of every peak,
+/// and the expected counts if `decay` gives the number of decays. A pure sum
+/// peak appears where cascades of gamma rays connect two levels without a
+/// transition between them, like the 2505 keV peak of 1173 and 1332 keV in
+/// ⁶⁰Co. Random summing and the veto of `options` do not apply, and the
+/// angular correlations only change the peaks of transitions.
+pub fn run_volume_prediction(
+    scheme: &LevelScheme,
+    decay: &Decay,
+    voxels: &[Voxel],
+    options: &RunOptions,
+) -> Result<Vec<PredictedPeak>, RunError> {
+    let decay = decay.is_known().then_some(decay);
+    let peaks = Sampler::new(scheme, &[], decay, voxels, options).peaks()?;
+    let mut accumulators = vec![PeakAccumulator::default(); peaks.len()];
+    run_blocks(
+        options,
+        peaks.len(),
+        || Sampler::new(scheme, &[], decay, voxels, options),
+        |sampler, r, out| sampler.predict(&peaks, r, out),
+        |row| {
+            for (acc, v) in accumulators.iter_mut().zip(row) {
+                acc.add(v);
+            }
+        },
+    )?;
+
+    let moments = |w: &Welford| (w.mean(), w.std());
+    let mut predicted: Vec<PredictedPeak> = peaks
+        .iter()
+        .zip(accumulators)
+        .map(|(&(j, i), acc)| PredictedPeak {
+            from: j,
+            to: i,
+            energy: scheme.levels[j].energy - scheme.levels[i].energy,
+            sum_peak: !scheme.has_transition(j, i),
+            response: moments(&acc.response),
+            response_no_summing: moments(&acc.response_no_summing),
+            expected: (acc.expected.count() > 0).then(|| moments(&acc.expected)),
+        })
+        .collect();
+    predicted.sort_by(|a, b| a.energy.total_cmp(&b.energy));
+    Ok(predicted)
+}
+
+/// `run_correction` for each spectrum of a detector array, first the single
+/// detectors and then the add-back groups, each named as in
+/// `DetectorArray::spectra`. Every spectrum uses the same seed.
+pub fn run_array_correction(
+    scheme: &LevelScheme,
+    observations: &[Observation],
+    decay: &Decay,
+    array: &DetectorArray,
+    options: &RunOptions,
+) -> Result<Vec<SpectrumResults>, RunError> {
+    array
+        .spectra()
+        .into_iter()
+        .map(|(name, peak, total)| {
+            run_correction(scheme, observations, decay, &peak, &total, options)
+                .map(|results| SpectrumResults { name, results })
+        })
+        .collect()
+}
+
+/// `run_correction` for an extended source, with the responses S and S0
+/// averaged over `voxels` by their weights before they are divided.
+pub fn run_volume_correction(
+    scheme: &LevelScheme,
+    observations: &[Observation],
+    decay: &Decay,
+    voxels: &[Voxel],
+    options: &RunOptions,
+) -> Result<Vec<Result<CorrectionResult, UndefinedTransition>>, RunError> {
+    let n_obs = observations.len();
+    let decay = decay.is_known().then_some(decay);
+    let mut accumulators = vec![
+        ObservationAccumulator {
+            correction: Accumulator::new(&options.quantiles, options.keep_samples),
+            eff_only: Welford::new(),
+            decay: DecayAccumulator::default(),
+            random: RandomAccumulator::default(),
+            suppressed: Welford::new(),
+            summing_out: Welford::new(),
+            summing_in: Welford::new(),
+        };
+        n_obs
+    ];
+
+    run_blocks(
+        options,
+        n_obs,
+        || Sampler::new(scheme, observations, decay, voxels, options),
+        Sampler::sample,
+        |row| {
+            for (acc, v) in accumulators.iter_mut().zip(row) {
+                acc.add(v);
+            }
+        },
+    )?;
+    let cascades = Sampler::new(scheme, observations, decay, voxels, options)
+        .cascades(options.top_cascades)?;
+
+    let energy_matrix = sum_correction::make_transition_energies(&scheme.branches, &scheme.levels);
+    Ok(observations
+        .iter()
+        .zip(accumulators.into_iter().zip(cascades))
+        .map(|(o, (acc, cascades))| {
+            if scheme.has_transition(o.from, o.to) {
+                Ok(CorrectionResult::new(
+                    o,
+                    energy_matrix.get(o.from, o.to),
+                    acc,
+                    decay,
+                    cascades,
+                ))
+            } else {
+                Err(UndefinedTransition {
+                    from: o.from,
+                    to: o.to,
+                })
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::efficiency::{EfficiencyCurve, Interpolation};
+    use crate::level_info::{Branch, Level};
+
+    /// ⁶⁰Co: 2505.7 keV -> 1332.5 keV -> ground state, with uncertain
+    /// branches and efficiencies so that the samples differ.
+    fn co60() -> (
+        LevelScheme,
+        Vec<Observation>,
+        EfficiencyModel,
+        TotalEfficiency,
+    ) {
+        let levels = vec![
+            Level::new(0, 0.0, 0.0, 0.0, 0.0),
+            Level::new(1, 1332.5, 0.0, 0.0, 0.0),
+            Level::new(2, 2505.7, 0.0, 1.0, 0.0),
+        ];
+        let branches = vec![
+            Branch::new(1, 0, 1.0, 0.0, 0.0, 0.0),
+            Branch::new(2, 1, 0.99, 0.01, 0.0, 0.0),
+            Branch::new(2, 0, 0.01, 0.01, 0.0, 0.0),
+        ];
+        let observations = vec![
+            Observation::new(2, 1, 1e5, 300.0),
+            Observation::new(1, 0, 1e5, 300.0),
+        ];
+        let energies = vec![100.0, 500.0, 1000.0, 1500.0, 3000.0];
+        let mut peak =
+            EfficiencyCurve::new(energies.clone(), vec![0.08, 0.03, 0.018, 0.013, 0.008]);
+        peak.deff = peak.eff.iter().map(|e| 0.02 * e).collect();
+        let mut total = EfficiencyCurve::new(energies, vec![0.25, 0.2, 0.16, 0.14, 0.12]);
+        total.deff = total.eff.iter().map(|e| 0.03 * e).collect();
+        (
+            LevelScheme::new(levels, branches),
+            observations,
+            peak.into(),
+            TotalEfficiency::Direct(total.into()),
+        )
+    }
+
+    fn run(options: &RunOptions) -> Vec<CorrectionResult> {
+        let (scheme, observations, peak, total) = co60();
+        run_correction(
+            &scheme,
+            &observations,
+            &Decay::default(),
+            &peak,
+            &total,
+            options,
+        )
+        .unwrap()
+        .into_iter()
+        .map(Result::unwrap)
+        .collect()
+    }
+
+    fn bits(results: &[CorrectionResult]) -> Vec<Vec<u64>> {
+        results
+            .iter()
+            .map(|r| {
+                let mut v = vec![r.correction, r.dcorrection, r.corrected, r.dcorrected];
+                v.extend(r.quantiles.iter().map(|q| q.1));
+                v.extend(r.samples.as_ref().unwrap());
+                v.iter().map(|x| x.to_bits()).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn seed_reproduces_run_for_any_thread_count() {
+        // More samples than one block, so the threads share the work.
+        let options = RunOptions {
+            n_samples: 5 * BLOCK_SIZE + 17,
+            threads: 1,
+            seed: 42,
+            quantiles: vec![0.16, 0.5, 0.84],
+            keep_samples: true,
+            ..Default::default()
+        };
+        let one = run(&options);
+        let four = run(&RunOptions {
+            threads: 4,
+            ..options.clone()
+        });
+        assert_eq!(bits(&one), bits(&four));
+        let other = run(&RunOptions {
+            seed: 43,
+            ..options
+        });
+        assert_ne!(bits(&one), bits(&other));
+    }
+
+    fn with_veto(eff: f64) -> Vec<CorrectionResult> {
+        let mut veto =
+            EfficiencyCurve::new(vec![100.0, 500.0, 1000.0, 1500.0, 3000.0], vec![eff; 5]);
+        veto.interpolation = Interpolation::Linear;
+        run(&RunOptions {
+            n_samples: 1000,
+            veto: Some(veto.into()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn veto_raises_the_correction() {
+        for r in with_veto(0.3) {
+            let (suppressed, _) = r.suppressed.unwrap().correction;
+            assert!(
+                suppressed > r.correction,
+                "{} keV: suppressed {suppressed}, unsuppressed {}",
+                r.energy,
+                r.correction
+            );
+        }
+    }
+
+    #[test]
+    fn veto_without_efficiency_changes_nothing() {
+        for r in with_veto(0.0) {
+            let (suppressed, dsuppressed) = r.suppressed.unwrap().correction;
+            assert!((suppressed - r.correction).abs() < 1e-12 * r.correction);
+            assert!((dsuppressed - r.dcorrection).abs() < 1e-12 * r.dcorrection);
+        }
+    }
+}
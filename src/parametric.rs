@@ -0,0 +1,478 @@
+use crate::efficiency::OutOfRange;
+use crate::error::{LineParser, ParseError, ParseErrorKind, SampleError};
+use crate::level_info::truncated_normal;
+/// This module handles efficiencies given as the parameters of a fitted
+/// function, with their covariance matrix, instead of a table.
+use rand::Rng;
+use rand_distr::{Distribution, StandardNormal};
+use std::f64::consts::FRAC_2_PI;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::str::FromStr;
+
+/// The functional form of a parametric efficiency, with x = ln(E/E0).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParametricForm {
+    /// Polynomial in the log of the energy (Debertin and Helmer, Gray and
+    /// Ahmad): ln ε = Σ a_k x^k with any number of parameters.
+    Debertin,
+    /// Jäckel, Westmeier and Patzelt (1987), which turns over at low energies:
+    /// ln ε = (a0 + a1 x + a2 x²) (2/π) arctan(exp(a3 + a4 x + a5 x³)) - 25.
+    JackelWestmeier,
+}
+
+impl ParametricForm {
+    /// Number of parameters the form takes, `None` if any number will do.
+    pub fn n_params(&self) -> Option<usize> {
+        match self {
+            ParametricForm::Debertin => None,
+            ParametricForm::JackelWestmeier => Some(6),
+        }
+    }
+
+    /// Log of the efficiency at x = ln(E/E0).
+    fn ln_eff(&self, params: &[f64], x: f64) -> f64 {
+        match self {
+            ParametricForm::Debertin => params.iter().rev().fold(0.0, |acc, &a| acc * x + a),
+            ParametricForm::JackelWestmeier => {
+                let a = params;
+                let turnover = (a[3] + a[4] * x + a[5] * x.powi(3)).exp().atan();
+                (a[0] + a[1] * x + a[2] * x * x) * FRAC_2_PI * turnover - 25.0
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParametricForm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ParametricForm::Debertin => "debertin",
+            ParametricForm::JackelWestmeier => "jackel-westmeier",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for ParametricForm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "debertin" | "log-poly" | "logpoly" => Ok(ParametricForm::Debertin),
+            "jackel-westmeier" | "jaeckel-westmeier" | "jackel" | "jaeckel" => {
+                Ok(ParametricForm::JackelWestmeier)
+            }
+            _ => Err(format!(
+                "unknown efficiency model `{s}`, expected debertin or jackel-westmeier"
+            )),
+        }
+    }
+}
+
+/// Lower triangular L with L Lᵀ = `cov` for a symmetric positive semi-definite
+/// n x n matrix stored by rows. Parameters without any variance get a zero
+/// column. `None` if the matrix is not symmetric positive semi-definite.
+fn cholesky(cov: &[f64], n: usize) -> Option<Vec<f64>> {
+    let scale = (0..n).map(|k| cov[k * n + k].abs()).fold(0.0, f64::max);
+    let tol = 1e-12 * scale;
+    let mut l = vec![0.0; n * n];
+    for i in 0..n {
+        for j in 0..=i {
+            if (cov[i * n + j] - cov[j * n + i]).abs() > tol {
+                return None;
+            }
+            let s = cov[i * n + j] - (0..j).map(|k| l[i * n + k] * l[j * n + k]).sum::<f64>();
+            if i == j {
+                if s < -tol {
+                    return None;
+                }
+                l[i * n + i] = s.max(0.0).sqrt();
+            } else if l[j * n + j] > 0.0 {
+                l[i * n + j] = s / l[j * n + j];
+            } else if s.abs() > tol {
+                // Covariance with a parameter that has no variance.
+                return None;
+            }
+        }
+    }
+    Some(l)
+}
+
+/// A parametric efficiency: the functional form, the reference energy E0
+/// (keV), the best fit parameters and their covariance matrix, stored by rows.
+/// `range` is where the fit is valid, outside of it `out_of_range` applies,
+/// with `OutOfRange::Extrapolate` simply evaluating the function. Like
+/// `EfficiencyCurve` this is plain data, threads build their own `function`.
+#[derive(Debug, Clone)]
+pub struct ParametricEfficiency {
+    pub form: ParametricForm,
+    pub e0: f64,
+    pub params: Vec<f64>,
+    pub covariance: Vec<f64>,
+    pub range: Option<(f64, f64)>,
+    pub scale_unc: f64,
+    pub out_of_range: OutOfRange,
+    chol: Vec<f64>,
+}
+
+impl ParametricEfficiency {
+    /// `None` if `covariance` is not a symmetric positive semi-definite matrix
+    /// of the size of `params`.
+    pub fn new(
+        form: ParametricForm,
+        e0: f64,
+        params: Vec<f64>,
+        covariance: Vec<f64>,
+    ) -> Option<Self> {
+        let n = params.len();
+        if covariance.len() != n * n {
+            return None;
+        }
+        let chol = cholesky(&covariance, n)?;
+        Some(Self {
+            form,
+            e0,
+            params,
+            covariance,
+            range: None,
+            scale_unc: 0.0,
+            out_of_range: OutOfRange::default(),
+            chol,
+        })
+    }
+
+    /// The function at the best fit parameters.
+    pub fn function(&self) -> ParametricFunction {
+        ParametricFunction {
+            form: self.form,
+            e0: self.e0,
+            params: self.params.clone(),
+            scale: 1.0,
+            range: self.range,
+            out_of_range: self.out_of_range,
+        }
+    }
+
+    /// Lowest and highest energy where the fit is valid, everything above zero
+    /// if no range was given.
+    pub fn range(&self) -> (f64, f64) {
+        self.range.unwrap_or((0.0, f64::INFINITY))
+    }
+
+    /// Whether the model has any uncertainty to sample.
+    pub fn is_uncertain(&self) -> bool {
+        self.scale_unc > 0.0 || self.chol.iter().any(|&l| l != 0.0)
+    }
+
+    /// Draw the parameters from their covariance, and one overall scale.
+    pub fn sample_params<R: Rng + ?Sized>(
+        &self,
+        r: &mut R,
+    ) -> Result<(Vec<f64>, f64), SampleError> {
+        let n = self.params.len();
+        let z: Vec<f64> = (0..n).map(|_| StandardNormal.sample(r)).collect();
+        let params = (0..n)
+            .map(|i| self.params[i] + (0..=i).map(|k| self.chol[i * n + k] * z[k]).sum::<f64>())
+            .collect();
+        Ok((params, truncated_normal(1.0, self.scale_unc, r)?))
+    }
+}
+
+/// A parametric efficiency evaluated with one set of parameters.
+#[derive(Debug, Clone)]
+pub struct ParametricFunction {
+    pub form: ParametricForm,
+    pub e0: f64,
+    pub params: Vec<f64>,
+    pub scale: f64,
+    pub range: Option<(f64, f64)>,
+    pub out_of_range: OutOfRange,
+}
+
+impl ParametricFunction {
+    pub fn set_params(&mut self, params: Vec<f64>, scale: f64) {
+        self.params = params;
+        self.scale = scale;
+    }
+
+    pub fn range(&self) -> (f64, f64) {
+        self.range.unwrap_or((0.0, f64::INFINITY))
+    }
+
+    /// Efficiency at `energy`, `None` outside of the range under `OutOfRange::Error`.
+    pub fn eval(&self, energy: f64) -> Option<f64> {
+        let (low, high) = self.range();
+        let energy = match self.out_of_range {
+            _ if (low..=high).contains(&energy) => energy,
+            OutOfRange::Error => return None,
+            OutOfRange::Clamp => energy.clamp(low, high),
+            OutOfRange::Extrapolate => energy,
+        };
+        Some(
+            self.scale
+                * self
+                    .form
+                    .ln_eff(&self.params, (energy / self.e0).ln())
+                    .exp(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ModelSection {
+    None,
+    Model,
+    Parameters,
+    Covariance,
+    Range,
+}
+
+impl ModelSection {
+    fn name(&self) -> &'static str {
+        match self {
+            ModelSection::None => "",
+            ModelSection::Model => "Model",
+            ModelSection::Parameters => "Parameters",
+            ModelSection::Covariance => "Covariance",
+            ModelSection::Range => "Range",
+        }
+    }
+}
+
+/// Whether the first line that is not blank or a comment is the `Model` header.
+pub fn is_parametric(file_content: &str) -> bool {
+    file_content
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty() && !l.starts_with('#'))
+        == Some("Model")
+}
+
+/// Read a parametric efficiency. The file has the sections
+///
+/// - `Model`: the form and optionally E0 in keV (default 1), e.g. `debertin 1000`.
+/// - `Parameters`: one parameter per line with an optional uncertainty.
+/// - `Covariance`: optional, the full covariance matrix, one row per line.
+///   Without it the parameters are uncorrelated.
+/// - `Range`: optional, the lowest and highest energy the fit is valid for.
+///
+/// Sections end at a blank line, and lines starting with `#` are comments.
+pub fn read_parametric(file_path: &str) -> Result<ParametricEfficiency, ParseError> {
+    let file_content = fs::read_to_string(file_path).map_err(|e| ParseError::read(file_path, e))?;
+    let mut section = ModelSection::None;
+    let mut model: Option<(ParametricForm, f64)> = None;
+    let mut params: Vec<f64> = Vec::new();
+    let mut dparams: Vec<f64> = Vec::new();
+    let mut rows: Vec<(usize, Vec<f64>)> = Vec::new();
+    let mut range = None;
+    for (i, line) in file_content.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            section = ModelSection::None;
+            continue;
+        }
+        if trimmed.starts_with('#') {
+            continue;
+        }
+        let mut p = LineParser::new(file_path, i + 1, section.name(), trimmed);
+        match section {
+            ModelSection::None => {
+                section = match trimmed {
+                    "Model" => ModelSection::Model,
+                    "Parameters" => ModelSection::Parameters,
+                    "Covariance" => ModelSection::Covariance,
+                    "Range" => ModelSection::Range,
+                    _ => return Err(p.error(trimmed, ParseErrorKind::UnknownSection)),
+                }
+            }
+            ModelSection::Model => {
+                let form: ParametricForm = p.next("efficiency model")?;
+                let e0 = p.optional("reference energy")?.unwrap_or(1.0);
+                model = Some((form, e0));
+            }
+            ModelSection::Parameters => {
+                params.push(p.next("parameter")?);
+                dparams.push(p.uncertainty("parameter uncertainty")?.unwrap_or(0.0));
+            }
+            ModelSection::Covariance => {
+                let mut row = Vec::with_capacity(p.n_fields());
+                while let Some(v) = p.optional("covariance")? {
+                    row.push(v);
+                }
+                rows.push((i + 1, row));
+            }
+            ModelSection::Range => {
+                range = Some((p.next("lowest energy")?, p.next("highest energy")?));
+            }
+        }
+    }
+
+    let whole_file =
+        |token: &str, kind: ParseErrorKind| ParseError::new(file_path, 0, "", token, kind);
+    let Some((form, e0)) = model else {
+        return Err(whole_file(
+            file_path,
+            ParseErrorKind::Missing("Model section"),
+        ));
+    };
+    let n = params.len();
+    match form.n_params() {
+        Some(expected) if n != expected => {
+            return Err(whole_file(
+                &format!("{n} parameters"),
+                ParseErrorKind::Count(expected),
+            ));
+        }
+        None if n == 0 => {
+            return Err(whole_file(
+                "no parameters",
+                ParseErrorKind::Missing("Parameters section"),
+            ));
+        }
+        _ => {}
+    }
+
+    let covariance = if rows.is_empty() {
+        let mut cov = vec![0.0; n * n];
+        for (k, d) in dparams.iter().enumerate() {
+            cov[k * n + k] = d * d;
+        }
+        cov
+    } else {
+        if rows.len() != n {
+            return Err(whole_file(
+                &format!("{} covariance rows", rows.len()),
+                ParseErrorKind::Count(n),
+            ));
+        }
+        if let Some((line_no, row)) = rows.iter().find(|(_, row)| row.len() != n) {
+            return Err(ParseError::new(
+                file_path,
+                *line_no,
+                ModelSection::Covariance.name(),
+                &format!("{} values", row.len()),
+                ParseErrorKind::Count(n),
+            ));
+        }
+        rows.into_iter().flat_map(|(_, row)| row).collect()
+    };
+
+    let mut model = ParametricEfficiency::new(form, e0, params, covariance)
+        .ok_or_else(|| whole_file("Covariance", ParseErrorKind::NotCovariance))?;
+    model.range = range;
+    Ok(model)
+}
+
+/// Write `model` in the format `read_parametric` reads, after `header` as
+/// comment lines.
+pub fn write_parametric(
+    model: &ParametricEfficiency,
+    header: &[String],
+    file_path: &str,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(fs::File::create(file_path)?);
+    for line in header.iter() {
+        writeln!(out, "# {line}")?;
+    }
+    writeln!(out, "Model\n{} {}\n", model.form, model.e0)?;
+    writeln!(out, "Parameters")?;
+    let n = model.params.len();
+    for (k, a) in model.params.iter().enumerate() {
+        writeln!(out, "{a:.10e} {:.10e}", model.covariance[k * n + k].sqrt())?;
+    }
+    writeln!(out, "\nCovariance")?;
+    for row in model.covariance.chunks(n) {
+        let row: Vec<String> = row.iter().map(|v| format!("{v:.10e}")).collect();
+        writeln!(out, "{}", row.join(" "))?;
+    }
+    if let Some((low, high)) = model.range {
+        writeln!(out, "\nRange\n{low} {high}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha12Rng;
+
+    fn uncorrelated(form: ParametricForm, e0: f64, params: Vec<f64>) -> ParametricEfficiency {
+        let n = params.len();
+        ParametricEfficiency::new(form, e0, params, vec![0.0; n * n]).unwrap()
+    }
+
+    #[test]
+    fn debertin_at_known_energy() {
+        // ln ε = -3 - 0.7 ln 2 + 0.1 ln² 2 at twice E0.
+        let model = uncorrelated(ParametricForm::Debertin, 1000.0, vec![-3.0, -0.7, 0.1]);
+        let eff = model.function().eval(2000.0).unwrap();
+        let expected = 0.03215595162185792;
+        assert!((eff / expected - 1.0).abs() < 1e-12, "{eff}");
+    }
+
+    #[test]
+    fn jackel_westmeier_at_known_energy() {
+        let params = vec![22.0, -0.6, -0.05, 0.0, 3.0, 0.1];
+        let model = uncorrelated(ParametricForm::JackelWestmeier, 1000.0, params);
+        // At E0 the turnover is (2/π) arctan(1) = 1/2, so ln ε = 22/2 - 25.
+        let eff = model.function().eval(1000.0).unwrap();
+        assert!((eff / (-14.0f64).exp() - 1.0).abs() < 1e-12, "{eff}");
+
+        let params = vec![22.0, -0.6, -0.05, 1.0, 3.0, 0.1];
+        let model = uncorrelated(ParametricForm::JackelWestmeier, 1000.0, params);
+        let eff = model.function().eval(2000.0).unwrap();
+        let expected = 0.017422282809449317;
+        assert!((eff / expected - 1.0).abs() < 1e-12, "{eff}");
+    }
+
+    #[test]
+    fn write_then_read_is_unchanged() {
+        let mut model = ParametricEfficiency::new(
+            ParametricForm::Debertin,
+            1000.0,
+            vec![-3.25, -0.75, 0.125],
+            vec![
+                0.01, 0.002, 0.0, 0.002, 0.0025, -0.0005, 0.0, -0.0005, 0.0004,
+            ],
+        )
+        .unwrap();
+        model.range = Some((50.0, 3000.0));
+        let path = std::env::temp_dir().join(format!("parametric-{}.dat", std::process::id()));
+        let path = path.to_str().unwrap();
+        write_parametric(&model, &["round trip".to_string()], path).unwrap();
+        let read = read_parametric(path);
+        fs::remove_file(path).unwrap();
+        let read = read.unwrap();
+        assert_eq!(read.form, model.form);
+        assert_eq!(read.e0, model.e0);
+        assert_eq!(read.params, model.params);
+        assert_eq!(read.covariance, model.covariance);
+        assert_eq!(read.range, model.range);
+    }
+
+    #[test]
+    fn sampled_params_have_the_covariance() {
+        // Standard deviations 0.2 and 0.1 with a correlation of 0.6.
+        let cov = vec![0.04, 0.012, 0.012, 0.01];
+        let model =
+            ParametricEfficiency::new(ParametricForm::Debertin, 1.0, vec![1.0, -2.0], cov.clone())
+                .unwrap();
+        let mut r = ChaCha12Rng::seed_from_u64(1);
+        let n = 20000;
+        let draws: Vec<Vec<f64>> = (0..n)
+            .map(|_| model.sample_params(&mut r).unwrap().0)
+            .collect();
+        for i in 0..2 {
+            for j in 0..2 {
+                let c = draws
+                    .iter()
+                    .map(|d| (d[i] - model.params[i]) * (d[j] - model.params[j]))
+                    .sum::<f64>()
+                    / n as f64;
+                assert!((c - cov[i * 2 + j]).abs() < 2e-3, "cov[{i}][{j}] = {c}");
+            }
+        }
+    }
+}
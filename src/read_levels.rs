@@ -1,125 +1,450 @@
-use crate::level_info::{Branch, Level, Observation};
+use crate::error::{LineParser, ParseError, ParseErrorKind};
+use crate::level_info::{
+    Annihilation, Atomic, Branch, Capture, ConversionShells, Decay, Level, LevelScheme,
+    Multipolarity, Observation, PairFormation, PositronFeeding, XRay,
+};
 /// This module handles the user input file.
 /// The input file is expected to be in the traditional LENA style
-/// You should have the following sections Energy-Levels, B-Values, and Observed-Values
+/// You should have the following sections Energy-Levels, B-Values, and Observed-Values,
+/// and optionally Decay, X-Rays, Capture, Conversion, Beta-Plus,
+/// Pair-Formation, Half-Lives, Spins and Multipolarities sections.
 use std::fs;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum FileSection {
     None,
     EnergyLevels,
     BValues,
     ObservedValues,
+    Decay,
+    XRays,
+    Capture,
+    Conversion,
+    BetaPlus,
+    PairFormation,
+    HalfLives,
+    Spins,
+    Multipolarities,
 }
 
-fn parse_header(line: &str) -> FileSection {
+impl FileSection {
+    fn name(&self) -> &'static str {
+        match self {
+            FileSection::None => "",
+            FileSection::EnergyLevels => "Energy-Levels",
+            FileSection::BValues => "B-Values",
+            FileSection::ObservedValues => "Observed-Values",
+            FileSection::Decay => "Decay",
+            FileSection::XRays => "X-Rays",
+            FileSection::Capture => "Capture",
+            FileSection::Conversion => "Conversion",
+            FileSection::BetaPlus => "Beta-Plus",
+            FileSection::PairFormation => "Pair-Formation",
+            FileSection::HalfLives => "Half-Lives",
+            FileSection::Spins => "Spins",
+            FileSection::Multipolarities => "Multipolarities",
+        }
+    }
+}
+
+fn parse_header(p: &LineParser, line: &str) -> Result<FileSection, ParseError> {
     match line {
-        "Energy-Levels" => FileSection::EnergyLevels,
-        "B-Values" => FileSection::BValues,
-        "Observed-Values" => FileSection::ObservedValues,
-        _ => panic!("{line} is not a valid section header!"),
-    }
-}
-
-fn parse_energy(line: &str, idx: i32) -> Level {
-    let mut parts = line.split_whitespace();
-    let energy: f64 = parts
-        .next()
-        .expect("Malformed Energy Line: {line}\n")
-        .parse()
-        .expect("Unable to parse level energy!\n");
-    let feeding: f64 = parts
-        .next()
-        .expect("Malformed Energy Line: {line}\n")
-        .parse()
-        .expect("Unable to parse feeding fraction!\n");
-    let dfeeding: f64 = parts
-        .next()
-        .expect("Malformed Energy Line: {line}\n")
-        .parse()
-        .expect("Unable to parse feeding fraction uncertainty!\n");
-    Level::new(idx as usize, energy, 0.0, feeding, dfeeding)
-}
-
-fn parse_branch(line: &str) -> Branch {
-    let mut parts = line.split_whitespace();
-    let from: usize = parts
-        .next()
-        .expect("Malformed Branch Line: {line}\n")
-        .parse()
-        .expect("Unable to parse branch from!\n");
-    let to: usize = parts
-        .next()
-        .expect("Malformed Energy Line: {line}\n")
-        .parse()
-        .expect("Unable to parse branch to!\n");
-    let val: f64 = parts
-        .next()
-        .expect("Malformed Energy Line: {line}\n")
-        .parse()
-        .expect("Unable to parse branch intensity!\n");
-    let dval: f64 = parts
-        .next()
-        .expect("Malformed Energy Line: {line}\n")
-        .parse()
-        .expect("Unable to parse branch intensity uncertainty!\n");
-
-    Branch::new(from, to, val, dval)
-}
-
-fn parse_obs(line: &str, n_samples: usize) -> Observation {
-    let mut parts = line.split_whitespace();
-    let from: usize = parts
-        .next()
-        .expect("Malformed Observation Line: {line}\n")
-        .parse()
-        .expect("Unable to parse observation from!\n");
-    let to: usize = parts
-        .next()
-        .expect("Malformed Observation Line: {line}\n")
-        .parse()
-        .expect("Unable to parse observation to!\n");
-    let counts: f64 = parts
-        .next()
-        .expect("Malformed Observation Line: {line}\n")
-        .parse()
-        .expect("Unable to parse observation counts!\n");
-    let dcounts: f64 = parts
-        .next()
-        .expect("Malformed Observation Line: {line}\n")
-        .parse()
-        .expect("Unable to parse observation counts uncertainty!\n");
-
-    Observation::new(from, to, counts, dcounts, n_samples)
-}
-
-pub fn read_input(
-    file_path: &str,
-    n_samples: usize,
-) -> (Vec<Level>, Vec<Branch>, Vec<Observation>) {
-    let file_content =
-        fs::read_to_string(file_path).expect(format!("Failed to read: {file_path}\n").as_str());
+        "Energy-Levels" => Ok(FileSection::EnergyLevels),
+        "B-Values" => Ok(FileSection::BValues),
+        "Observed-Values" => Ok(FileSection::ObservedValues),
+        "Decay" => Ok(FileSection::Decay),
+        "X-Rays" => Ok(FileSection::XRays),
+        "Capture" => Ok(FileSection::Capture),
+        "Conversion" => Ok(FileSection::Conversion),
+        "Beta-Plus" => Ok(FileSection::BetaPlus),
+        "Pair-Formation" => Ok(FileSection::PairFormation),
+        "Half-Lives" => Ok(FileSection::HalfLives),
+        "Spins" => Ok(FileSection::Spins),
+        "Multipolarities" => Ok(FileSection::Multipolarities),
+        _ => Err(p.error(line, ParseErrorKind::UnknownSection)),
+    }
+}
+
+fn parse_energy(p: &mut LineParser, idx: i32) -> Result<Level, ParseError> {
+    // Lines are either "energy feeding dfeeding" or "energy denergy feeding dfeeding",
+    // anything else is rejected rather than guessed at.
+    let has_denergy = match p.n_fields() {
+        3 => false,
+        4 => true,
+        _ => return Err(p.error(p.line(), ParseErrorKind::Fields("3 or 4"))),
+    };
+    let energy: f64 = p.next("level energy")?;
+    let denergy: f64 = if has_denergy {
+        p.next_uncertainty("level energy uncertainty")?
+    } else {
+        0.0
+    };
+    let feeding: f64 = p.next("feeding fraction")?;
+    let dfeeding: f64 = p.next_uncertainty("feeding fraction uncertainty")?;
+    Ok(Level::new(idx as usize, energy, denergy, feeding, dfeeding))
+}
+
+/// A level and its half-life in seconds, with an optional uncertainty.
+fn parse_half_life(p: &mut LineParser) -> Result<(usize, (f64, f64)), ParseError> {
+    let level: usize = p.next("half-life level")?;
+    let half_life: f64 = p.next("half-life")?;
+    let dhalf_life = p.uncertainty("half-life uncertainty")?.unwrap_or(0.0);
+    Ok((level, (half_life, dhalf_life)))
+}
+
+fn parse_branch(p: &mut LineParser) -> Result<Branch, ParseError> {
+    let from: usize = p.next("branch from")?;
+    let to: usize = p.next("branch to")?;
+    let val: f64 = p.next("branch intensity")?;
+    let dval: f64 = p.next_uncertainty("branch intensity uncertainty")?;
+    // The internal conversion coefficient and its uncertainty are optional.
+    let alpha: f64 = p.optional("conversion coefficient")?.unwrap_or(0.0);
+    let dalpha: f64 = p
+        .uncertainty("conversion coefficient uncertainty")?
+        .unwrap_or(0.0);
+
+    Ok(Branch::new(from, to, val, dval, alpha, dalpha))
+}
+
+fn parse_obs(p: &mut LineParser) -> Result<Observation, ParseError> {
+    let from: usize = p.next("observation from")?;
+    let to: usize = p.next("observation to")?;
+    let counts: f64 = p.next("observation counts")?;
+    let dcounts: f64 = p.next_uncertainty("observation counts uncertainty")?;
+    // The emission probability per decay and its uncertainty are optional.
+    let mut o = Observation::new(from, to, counts, dcounts);
+    if let Some(emission) = p.optional("emission probability")? {
+        let demission = p
+            .uncertainty("emission probability uncertainty")?
+            .unwrap_or(0.0);
+        o.emission = Some((emission, demission));
+    }
+    Ok(o)
+}
+
+/// Decay lines are a quantity followed by its value and, except for the
+/// times, its uncertainty.
+fn parse_decay(p: &mut LineParser, decay: &mut Decay) -> Result<(), ParseError> {
+    let quantity: String = p.next("decay quantity")?;
+    match quantity.as_str() {
+        "decays" => {
+            decay.decays = Some((p.next("decays")?, p.next_uncertainty("decays uncertainty")?))
+        }
+        "activity" => {
+            decay.activity = Some((
+                p.next("activity")?,
+                p.next_uncertainty("activity uncertainty")?,
+            ))
+        }
+        "half-life" => {
+            decay.half_life = Some((
+                p.next("half-life")?,
+                p.next_uncertainty("half-life uncertainty")?,
+            ))
+        }
+        "live-time" => decay.live_time = Some(p.next("live time")?),
+        "real-time" => decay.real_time = Some(p.next("real time")?),
+        "branching" => {
+            decay.branching = Some((
+                p.next("branching")?,
+                p.next_uncertainty("branching uncertainty")?,
+            ))
+        }
+        _ => return Err(p.error(&quantity, ParseErrorKind::Invalid("decay quantity"))),
+    }
+    Ok(())
+}
+
+fn parse_xray(p: &mut LineParser) -> Result<XRay, ParseError> {
+    Ok(XRay {
+        shell: p.next("shell")?,
+        energy: p.next("X-ray energy")?,
+        intensity: p.next("X-rays per vacancy")?,
+        dintensity: p
+            .uncertainty("X-rays per vacancy uncertainty")?
+            .unwrap_or(0.0),
+    })
+}
+
+fn parse_capture(p: &mut LineParser) -> Result<Capture, ParseError> {
+    Ok(Capture {
+        level: p.next("capture level")?,
+        fraction: p.next("capture fraction")?,
+        k: p.next("K capture probability")?,
+        l: p.next("L capture probability")?,
+    })
+}
+
+fn parse_conversion(p: &mut LineParser) -> Result<ConversionShells, ParseError> {
+    Ok(ConversionShells {
+        from: p.next("conversion from")?,
+        to: p.next("conversion to")?,
+        k: p.next("K conversion fraction")?,
+        l: p.next("L conversion fraction")?,
+    })
+}
+
+fn parse_positron(p: &mut LineParser) -> Result<PositronFeeding, ParseError> {
+    Ok(PositronFeeding {
+        level: p.next("β⁺ level")?,
+        fraction: p.next("β⁺ fraction")?,
+        dfraction: p.uncertainty("β⁺ fraction uncertainty")?.unwrap_or(0.0),
+    })
+}
+
+fn parse_pair(p: &mut LineParser) -> Result<PairFormation, ParseError> {
+    Ok(PairFormation {
+        from: p.next("pair formation from")?,
+        to: p.next("pair formation to")?,
+        coefficient: p.next("pair formation coefficient")?,
+        dcoefficient: p
+            .uncertainty("pair formation coefficient uncertainty")?
+            .unwrap_or(0.0),
+    })
+}
+
+/// A level and its spin, written as e.g. 2, 5/2 or 3/2- with the parity ignored.
+fn parse_spin(p: &mut LineParser) -> Result<(usize, f64), ParseError> {
+    let level: usize = p.next("spin level")?;
+    let token: String = p.next("spin")?;
+    let digits = token.trim_end_matches(['+', '-']);
+    let spin = match digits.split_once('/') {
+        Some((num, den)) => num
+            .parse::<f64>()
+            .ok()
+            .zip(den.parse::<f64>().ok())
+            .map(|(n, d)| n / d),
+        None => digits.parse().ok(),
+    };
+    // Spins are whole or half numbers.
+    match spin {
+        Some(j) if j >= 0.0 && (2.0 * j).fract() == 0.0 => Ok((level, j)),
+        _ => Err(p.error(&token, ParseErrorKind::Invalid("spin"))),
+    }
+}
+
+/// A transition and its multipolarity, the order as e.g. 2, E2 or M1 followed by an
+/// optional mixing ratio and its uncertainty.
+fn parse_multipolarity(p: &mut LineParser) -> Result<(usize, usize, Multipolarity), ParseError> {
+    let from: usize = p.next("multipolarity from")?;
+    let to: usize = p.next("multipolarity to")?;
+    let token: String = p.next("multipole order")?;
+    let order = match token.trim_start_matches(['E', 'M']).parse::<u32>() {
+        Ok(l) if l > 0 => l,
+        _ => return Err(p.error(&token, ParseErrorKind::Invalid("multipole order"))),
+    };
+    let mixing = p.optional("mixing ratio")?.unwrap_or(0.0);
+    let dmixing = p.uncertainty("mixing ratio uncertainty")?.unwrap_or(0.0);
+    Ok((
+        from,
+        to,
+        Multipolarity {
+            order,
+            mixing,
+            dmixing,
+        },
+    ))
+}
+
+/// Everything in an input file.
+#[derive(Debug, Clone)]
+pub struct Input {
+    pub scheme: LevelScheme,
+    pub observations: Vec<Observation>,
+    pub decay: Decay,
+}
+
+/// Read the level scheme and the observed peak areas from a LENA style input file.
+pub fn read_input(file_path: &str) -> Result<(LevelScheme, Vec<Observation>), ParseError> {
+    read_source(file_path).map(|input| (input.scheme, input.observations))
+}
+
+/// Read a LENA style input file, including its Decay section.
+pub fn read_source(file_path: &str) -> Result<Input, ParseError> {
+    let file_content = fs::read_to_string(file_path).map_err(|e| ParseError::read(file_path, e))?;
     let mut current_section = FileSection::None;
     let mut levels: Vec<Level> = Vec::new();
     let mut branchs: Vec<Branch> = Vec::new();
     let mut obs: Vec<Observation> = Vec::new();
+    let mut decay = Decay::default();
+    let mut atomic = Atomic::default();
+    let mut annihilation = Annihilation::default();
+    let mut half_lives: Vec<(usize, (f64, f64))> = Vec::new();
+    let mut spins: Vec<(usize, f64)> = Vec::new();
+    let mut multipolarities: Vec<(usize, usize, usize, Multipolarity)> = Vec::new();
+    // Line numbers of the branches and observations, so level indices can be checked
+    // once all of the levels are known.
+    let mut references: Vec<(usize, FileSection, usize, usize)> = Vec::new();
     let mut idx = 0;
     let mut counter = || {
         idx += 1;
         idx - 1
     };
-    for line in file_content.lines() {
+    for (i, line) in file_content.lines().enumerate() {
         let trimmed = line.trim();
-        if line.is_empty() {
+        if trimmed.is_empty() {
             current_section = FileSection::None;
             continue;
         }
+        let mut p = LineParser::new(file_path, i + 1, current_section.name(), trimmed);
         match current_section {
-            FileSection::None => current_section = parse_header(trimmed),
-            FileSection::EnergyLevels => levels.push(parse_energy(trimmed, counter())),
-            FileSection::BValues => branchs.push(parse_branch(trimmed)),
-            FileSection::ObservedValues => obs.push(parse_obs(trimmed, n_samples)),
+            FileSection::None => current_section = parse_header(&p, trimmed)?,
+            FileSection::EnergyLevels => levels.push(parse_energy(&mut p, counter())?),
+            FileSection::BValues => {
+                let b = parse_branch(&mut p)?;
+                references.push((i + 1, current_section, b.from, b.to));
+                branchs.push(b);
+            }
+            FileSection::ObservedValues => {
+                let o = parse_obs(&mut p)?;
+                references.push((i + 1, current_section, o.from, o.to));
+                obs.push(o);
+            }
+            FileSection::Decay => parse_decay(&mut p, &mut decay)?,
+            FileSection::XRays => atomic.xrays.push(parse_xray(&mut p)?),
+            FileSection::Capture => {
+                let c = parse_capture(&mut p)?;
+                references.push((i + 1, current_section, c.level, c.level));
+                atomic.captures.push(c);
+            }
+            FileSection::Conversion => {
+                let c = parse_conversion(&mut p)?;
+                if atomic
+                    .conversions
+                    .iter()
+                    .any(|d| d.from == c.from && d.to == c.to)
+                {
+                    return Err(p.error(trimmed, ParseErrorKind::DuplicateTransition(c.from, c.to)));
+                }
+                references.push((i + 1, current_section, c.from, c.to));
+                atomic.conversions.push(c);
+            }
+            FileSection::BetaPlus => {
+                let f = parse_positron(&mut p)?;
+                references.push((i + 1, current_section, f.level, f.level));
+                annihilation.feedings.push(f);
+            }
+            FileSection::PairFormation => {
+                let pair = parse_pair(&mut p)?;
+                references.push((i + 1, current_section, pair.from, pair.to));
+                annihilation.pairs.push(pair);
+            }
+            FileSection::HalfLives => {
+                let (level, half_life) = parse_half_life(&mut p)?;
+                references.push((i + 1, current_section, level, level));
+                half_lives.push((level, half_life));
+            }
+            FileSection::Spins => {
+                let (level, spin) = parse_spin(&mut p)?;
+                references.push((i + 1, current_section, level, level));
+                spins.push((level, spin));
+            }
+            FileSection::Multipolarities => {
+                let (from, to, m) = parse_multipolarity(&mut p)?;
+                references.push((i + 1, current_section, from, to));
+                multipolarities.push((i + 1, from, to, m));
+            }
+        }
+    }
+
+    let lines: Vec<&str> = file_content.lines().collect();
+    for (line_no, section, from, to) in references {
+        if let Some(&bad) = [from, to].iter().find(|&&l| l >= levels.len()) {
+            return Err(ParseError::new(
+                file_path,
+                line_no,
+                section.name(),
+                lines[line_no - 1].trim(),
+                ParseErrorKind::UnknownLevel(bad),
+            ));
+        }
+        if matches!(section, FileSection::BValues) && levels[from].energy <= levels[to].energy {
+            return Err(ParseError::new(
+                file_path,
+                line_no,
+                section.name(),
+                lines[line_no - 1].trim(),
+                ParseErrorKind::NotDownward(from, to),
+            ));
+        }
+    }
+    for (level, half_life) in half_lives {
+        levels[level].half_life = Some(half_life);
+    }
+    for (level, spin) in spins {
+        levels[level].spin = Some(spin);
+    }
+    for (line_no, from, to, m) in multipolarities {
+        let Some(branch) = branchs.iter_mut().find(|b| b.from == from && b.to == to) else {
+            return Err(ParseError::new(
+                file_path,
+                line_no,
+                FileSection::Multipolarities.name(),
+                lines[line_no - 1].trim(),
+                ParseErrorKind::UnknownTransition(from, to),
+            ));
+        };
+        branch.multipolarity = Some(m);
+    }
+    let mut scheme = LevelScheme::new(levels, branchs);
+    scheme.atomic = atomic;
+    scheme.annihilation = annihilation;
+    Ok(Input {
+        scheme,
+        observations: obs,
+        decay,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn energy(line: &str) -> Result<Level, ParseError> {
+        parse_energy(&mut LineParser::new("test", 1, "Energy-Levels", line), 0)
+    }
+
+    #[test]
+    fn energy_without_uncertainty() {
+        let level = energy("1332.5 0.2 0.01").unwrap();
+        assert_eq!(level.energy, 1332.5);
+        assert_eq!(level.denergy, 0.0);
+        assert_eq!(level.feeding, 0.2);
+        assert_eq!(level.dfeeding, 0.01);
+    }
+
+    #[test]
+    fn energy_with_uncertainty() {
+        let level = energy("1332.5 0.1 0.2 0.01").unwrap();
+        assert_eq!(level.energy, 1332.5);
+        assert_eq!(level.denergy, 0.1);
+        assert_eq!(level.feeding, 0.2);
+        assert_eq!(level.dfeeding, 0.01);
+    }
+
+    #[test]
+    fn negative_uncertainty() {
+        let err = energy("1332.5 0.2 -0.01").unwrap_err();
+        assert!(matches!(err.kind, ParseErrorKind::Negative(_)), "{err}");
+        let err = energy("1332.5 -0.1 0.2 0.01").unwrap_err();
+        assert!(matches!(err.kind, ParseErrorKind::Negative(_)), "{err}");
+    }
+
+    #[test]
+    fn energy_with_extra_columns() {
+        // A half-life after either layout would be ambiguous, so both are rejected.
+        for line in ["1332.5 0.2 0.01 1.5e-7 1e-8", "1332.5 0.1 0.2 0.01 1.5e-7"] {
+            let err = energy(line).unwrap_err();
+            assert!(matches!(err.kind, ParseErrorKind::Fields(_)), "{err}");
         }
+        assert!(energy("1332.5 0.2").is_err());
+    }
+
+    #[test]
+    fn half_life() {
+        let mut p = LineParser::new("test", 1, "Half-Lives", "2 1.5e-7 1e-8");
+        assert_eq!(parse_half_life(&mut p).unwrap(), (2, (1.5e-7, 1e-8)));
+        let mut p = LineParser::new("test", 1, "Half-Lives", "2 1.5e-7");
+        assert_eq!(parse_half_life(&mut p).unwrap(), (2, (1.5e-7, 0.0)));
     }
-    (levels, branchs, obs)
 }
@@ -0,0 +1,222 @@
+//! Streaming statistics used to summarize Monte Carlo samples without
+//! keeping them in memory.
+
+/// Running mean and variance using Welford's algorithm.
+#[derive(Debug, Clone, Default)]
+pub struct Welford {
+    count: usize,
+    mean: f64,
+    m2: f64,
+}
+
+impl Welford {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, x: f64) {
+        self.count += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 { f64::NAN } else { self.mean }
+    }
+
+    /// The sample standard deviation.
+    pub fn std(&self) -> f64 {
+        if self.count < 2 {
+            f64::NAN
+        } else {
+            f64::sqrt(self.m2 / (self.count - 1) as f64)
+        }
+    }
+}
+
+/// Estimate of a single quantile with the P² algorithm of Jain and Chlamtac
+/// (1985), which only keeps five markers.
+#[derive(Debug, Clone)]
+pub struct P2Quantile {
+    p: f64,
+    count: usize,
+    // Marker heights, actual and desired positions, and desired position increments.
+    q: [f64; 5],
+    n: [f64; 5],
+    np: [f64; 5],
+    dn: [f64; 5],
+}
+
+impl P2Quantile {
+    /// Panics unless 0 < p < 1.
+    pub fn new(p: f64) -> Self {
+        assert!(p > 0.0 && p < 1.0, "quantile {p} is not between 0 and 1");
+        Self {
+            p,
+            count: 0,
+            q: [0.0; 5],
+            n: [0.0, 1.0, 2.0, 3.0, 4.0],
+            np: [0.0, 2.0 * p, 4.0 * p, 2.0 + 2.0 * p, 4.0],
+            dn: [0.0, p / 2.0, p, (1.0 + p) / 2.0, 1.0],
+        }
+    }
+
+    pub fn p(&self) -> f64 {
+        self.p
+    }
+
+    pub fn add(&mut self, x: f64) {
+        if self.count < 5 {
+            self.q[self.count] = x;
+            self.count += 1;
+            if self.count == 5 {
+                self.q.sort_by(f64::total_cmp);
+            }
+            return;
+        }
+        self.count += 1;
+
+        let k = if x < self.q[0] {
+            self.q[0] = x;
+            0
+        } else if x >= self.q[4] {
+            self.q[4] = x;
+            3
+        } else {
+            (1..5).find(|&i| x < self.q[i]).unwrap() - 1
+        };
+        for i in k + 1..5 {
+            self.n[i] += 1.0;
+        }
+        for i in 0..5 {
+            self.np[i] += self.dn[i];
+        }
+
+        for i in 1..4 {
+            let d = self.np[i] - self.n[i];
+            if (d >= 1.0 && self.n[i + 1] - self.n[i] > 1.0)
+                || (d <= -1.0 && self.n[i - 1] - self.n[i] < -1.0)
+            {
+                let d = d.signum();
+                let qp = self.parabolic(i, d);
+                self.q[i] = if self.q[i - 1] < qp && qp < self.q[i + 1] {
+                    qp
+                } else {
+                    self.linear(i, d)
+                };
+                self.n[i] += d;
+            }
+        }
+    }
+
+    fn parabolic(&self, i: usize, d: f64) -> f64 {
+        let (q, n) = (&self.q, &self.n);
+        q[i] + d / (n[i + 1] - n[i - 1])
+            * ((n[i] - n[i - 1] + d) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+                + (n[i + 1] - n[i] - d) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]))
+    }
+
+    fn linear(&self, i: usize, d: f64) -> f64 {
+        let j = if d > 0.0 { i + 1 } else { i - 1 };
+        self.q[i] + d * (self.q[j] - self.q[i]) / (self.n[j] - self.n[i])
+    }
+
+    pub fn value(&self) -> f64 {
+        if self.count == 0 {
+            return f64::NAN;
+        }
+        if self.count < 5 {
+            // Too few samples for the markers, use the exact order statistic.
+            let mut first = self.q[..self.count].to_vec();
+            first.sort_by(f64::total_cmp);
+            let idx = (self.p * (self.count - 1) as f64).round() as usize;
+            return first[idx];
+        }
+        self.q[2]
+    }
+}
+
+/// Summary of a stream of samples: always the mean and standard deviation,
+/// the requested quantiles, and the samples themselves only if asked for.
+#[derive(Debug, Clone)]
+pub struct Accumulator {
+    pub moments: Welford,
+    pub quantiles: Vec<P2Quantile>,
+    pub samples: Option<Vec<f64>>,
+}
+
+impl Accumulator {
+    pub fn new(quantiles: &[f64], keep_samples: bool) -> Self {
+        Self {
+            moments: Welford::new(),
+            quantiles: quantiles.iter().map(|&p| P2Quantile::new(p)).collect(),
+            samples: keep_samples.then(Vec::new),
+        }
+    }
+
+    pub fn add(&mut self, x: f64) {
+        self.moments.add(x);
+        for q in self.quantiles.iter_mut() {
+            q.add(x);
+        }
+        if let Some(s) = &mut self.samples {
+            s.push(x);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha12Rng;
+    use rand_distr::{Distribution, Normal};
+
+    #[test]
+    fn welford_matches_two_pass() {
+        let xs = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+        let mut w = Welford::new();
+        xs.iter().for_each(|&x| w.add(x));
+        assert_eq!(w.count(), 8);
+        assert!((w.mean() - 5.0).abs() < 1e-12);
+        assert!((w.std() - f64::sqrt(32.0 / 7.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn p2_matches_sorted_sample() {
+        let mut r = ChaCha12Rng::seed_from_u64(7);
+        let normal = Normal::new(1.0, 0.1).unwrap();
+        let xs: Vec<f64> = (0..100_000).map(|_| normal.sample(&mut r)).collect();
+        let mut acc = Accumulator::new(&[0.16, 0.5, 0.84], false);
+        xs.iter().for_each(|&x| acc.add(x));
+
+        let n = xs.len() as f64;
+        let mean = xs.iter().sum::<f64>() / n;
+        let var = xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0);
+        assert!((acc.moments.mean() - mean).abs() < 1e-12);
+        assert!((acc.moments.std() - var.sqrt()).abs() < 1e-12);
+
+        let mut sorted = xs.clone();
+        sorted.sort_by(f64::total_cmp);
+        for q in acc.quantiles.iter() {
+            let exact = sorted[(q.p() * (n - 1.0)).round() as usize];
+            assert!(
+                (q.value() - exact).abs() < 2e-3,
+                "q{} = {} against {exact}",
+                q.p(),
+                q.value()
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn quantile_outside_unit_interval() {
+        P2Quantile::new(1.5);
+    }
+}
@@ -1,53 +1,74 @@
 use crate::{
-    efficiency::Efficiency,
-    level_info::{Branch, Level},
+    angular::{Attenuation, Correlation},
+    efficiency::{EfficiencyEvaluator, TotalEvaluator},
+    error::EfficiencyRangeError,
+    level_info::{
+        Annihilation, Atomic, Branch, ELECTRON_MASS, Level, LevelScheme, Observation, Shell,
+    },
 };
-use rgsl::{blas, MatrixF64, VectorF64};
+use rgsl::{MatrixF64, VectorF64, blas};
+use std::fmt;
 
+/// Overwrite `B` with (I - t)^-1 B, where t is strictly lower triangular.
+/// The power series sum_k t^k terminates because t is nilpotent, so it is
+/// exactly this unit triangular inverse.
 #[allow(non_snake_case)]
-fn lower_triangular_multiply(A: &MatrixF64, B: &mut MatrixF64) {
-    blas::level3::dtrmm(
+fn unit_lower_solve(t: &MatrixF64, B: &mut MatrixF64) {
+    blas::level3::dtrsm(
         rgsl::CblasSide::Left,
         rgsl::CblasUplo::Lower,
         rgsl::CblasTranspose::NoTranspose,
-        rgsl::CblasDiag::NonUnit,
+        rgsl::CblasDiag::Unit,
         1.0,
-        A,
+        &negated(t),
         B,
     )
     .unwrap();
 }
 
-#[allow(non_snake_case)]
-fn lower_triangular_vector_multiply_reverse(x: &mut VectorF64, A: &MatrixF64) {
-    blas::level2::dtrmv(
+/// Overwrite `v` with (I - t)^-1 v, or (I - t)^-T v if `transpose`.
+fn unit_lower_vector_solve(t: &MatrixF64, v: &mut VectorF64, transpose: bool) {
+    let trans = if transpose {
+        rgsl::CblasTranspose::Transpose
+    } else {
+        rgsl::CblasTranspose::NoTranspose
+    };
+    blas::level2::dtrsv(
         rgsl::CblasUplo::Lower,
-        rgsl::CblasTranspose::Transpose,
-        rgsl::CblasDiag::NonUnit,
-        A,
-        x,
+        trans,
+        rgsl::CblasDiag::Unit,
+        &negated(t),
+        v,
     )
     .unwrap();
 }
 
-#[allow(non_snake_case)]
-fn matrix_multiply(A: &MatrixF64, B: &MatrixF64, C: &mut MatrixF64) {
-    blas::level3::dgemm(
-        rgsl::CblasTranspose::NoTranspose,
-        rgsl::CblasTranspose::NoTranspose,
-        1.0,
-        A,
-        B,
-        0.0,
-        C,
-    )
-    .unwrap()
+/// -t, with the unit diagonal of I - t implied by CblasDiag::Unit.
+fn negated(t: &MatrixF64) -> MatrixF64 {
+    let mut neg = make_square_matrix(t.size1(), "I - t");
+    neg.copy_from(t).unwrap();
+    neg.scale(-1.0).unwrap();
+    neg
 }
 
 fn make_square_matrix(n: usize, name: &str) -> MatrixF64 {
-    MatrixF64::new(n, n).expect(format!("Failed to allocate {name}!").as_str())
+    MatrixF64::new(n, n).unwrap_or_else(|| panic!("Failed to allocate {name}!"))
+}
+
+/// t with column k, or row k if `rows`, multiplied by p_k.
+fn scaled(t: &MatrixF64, p: &VectorF64, rows: bool) -> MatrixF64 {
+    let n = t.size1();
+    let mut out = make_square_matrix(n, "scaled");
+    for j in 0..n {
+        for i in 0..n {
+            let k = if rows { j } else { i };
+            out.set(j, i, t.get(j, i) * p.get(k));
+        }
+    }
+    out
 }
 
+/// The branching matrix x and the normalized feeding vector f of Semkow.
 pub fn make_x_and_f_matrix(branchs: &[Branch], levels: &[Level]) -> (MatrixF64, VectorF64) {
     // First we construct the x matrix, Eq.2 from Semkow
     let n_levels = levels.len();
@@ -80,6 +101,20 @@ pub fn make_x_and_f_matrix(branchs: &[Branch], levels: &[Level]) -> (MatrixF64,
     (x, f)
 }
 
+/// The c matrix of Eq.4a from Semkow, c_ji = x_ji / (1 + alpha_ji), which is the
+/// probability that the transition j -> i proceeds by gamma emission.
+pub fn make_c_matrix(x: &MatrixF64, branchs: &[Branch]) -> MatrixF64 {
+    let mut c = make_square_matrix(x.size1(), "c");
+    c.copy_from(x).unwrap();
+    for branch in branchs.iter() {
+        let j = branch.from;
+        let i = branch.to;
+        c.set(j, i, x.get(j, i) / (1.0 + branch.alpha));
+    }
+    c
+}
+
+/// Matrix of transition energies, E_j - E_i, for every branch j -> i.
 pub fn make_transition_energies(branchs: &[Branch], levels: &[Level]) -> MatrixF64 {
     let n_levels = levels.len();
     let mut energy_matrix =
@@ -92,11 +127,12 @@ pub fn make_transition_energies(branchs: &[Branch], levels: &[Level]) -> MatrixF
     energy_matrix
 }
 
+/// Peak and total efficiency matrices evaluated at the transition energies.
 pub fn make_eff_matrix(
     energy_matrix: &MatrixF64,
-    peak_spline: &mut Efficiency,
-    total_spline: &mut Efficiency,
-) -> (MatrixF64, MatrixF64) {
+    peak_eff: &mut EfficiencyEvaluator,
+    total_eff: &mut TotalEvaluator,
+) -> Result<(MatrixF64, MatrixF64), EfficiencyRangeError> {
     let n_levels = energy_matrix.size1();
     let mut peak_matrix = MatrixF64::new(n_levels, n_levels)
         .expect("Failed to allocate matrix for peak efficiencies");
@@ -106,121 +142,917 @@ pub fn make_eff_matrix(
         for i in 0..n_levels {
             let e = energy_matrix.get(j, i);
             if e > 0.0 {
-                peak_matrix.set(j, i, peak_spline.eval(e));
-                tot_matrix.set(j, i, total_spline.eval(e));
+                let what = || format!("transition {j} -> {i}");
+                let peak = peak_eff
+                    .eval(e)
+                    .ok_or_else(|| range_error(peak_eff.range(), "peak", what(), e))?;
+                let total = total_eff
+                    .eval(e, peak)
+                    .ok_or_else(|| range_error(total_eff.range(), total_eff.name(), what(), e))?;
+                peak_matrix.set(j, i, peak);
+                tot_matrix.set(j, i, total);
             }
         }
     }
-    (peak_matrix, tot_matrix)
+    Ok((peak_matrix, tot_matrix))
 }
 
-#[allow(non_snake_case)]
+fn range_error(range: (f64, f64), curve: &str, what: String, energy: f64) -> EfficiencyRangeError {
+    EfficiencyRangeError {
+        curve: curve.to_string(),
+        what,
+        energy,
+        range,
+    }
+}
+
+/// Total efficiency for a photon that is not part of the cascade. The peak
+/// efficiency is only needed, and checked, for a peak-to-total ratio.
+fn total_at(
+    energy: f64,
+    what: &str,
+    peak_eff: &mut EfficiencyEvaluator,
+    total_eff: &mut TotalEvaluator,
+) -> Result<f64, EfficiencyRangeError> {
+    let peak = if total_eff.needs_peak() {
+        peak_eff
+            .eval(energy)
+            .ok_or_else(|| range_error(peak_eff.range(), "peak", what.to_string(), energy))?
+    } else {
+        f64::NAN
+    };
+    total_eff.eval(energy, peak).ok_or_else(|| {
+        range_error(
+            total_eff.range(),
+            total_eff.name(),
+            what.to_string(),
+            energy,
+        )
+    })
+}
+
+/// Total count rate implied by the observed peaks over `live_time`: each
+/// peak stands for counts T/P events in the spectrum. Events that sum are
+/// counted once for each of their photons, so this slightly overestimates
+/// the rate of a source with cascades. Observations that are not transitions
+/// of `energies` are skipped.
+pub fn estimate_count_rate(
+    observations: &[Observation],
+    energies: &MatrixF64,
+    live_time: f64,
+    peak_eff: &mut EfficiencyEvaluator,
+    total_eff: &mut TotalEvaluator,
+) -> Result<f64, EfficiencyRangeError> {
+    let mut events = 0.0;
+    for o in observations.iter() {
+        let e = energies.get(o.from, o.to);
+        if e <= 0.0 {
+            continue;
+        }
+        let what = || format!("transition {} -> {}", o.from, o.to);
+        let peak = peak_eff
+            .eval(e)
+            .ok_or_else(|| range_error(peak_eff.range(), "peak", what(), e))?;
+        let total = total_eff
+            .eval(e, peak)
+            .ok_or_else(|| range_error(total_eff.range(), total_eff.name(), what(), e))?;
+        events += o.counts * total / peak;
+    }
+    Ok(events / live_time)
+}
+
+/// Detection probabilities of photons that accompany the cascade without
+/// being one of its transitions: with the feeding of each level, e.g. X-rays
+/// after electron capture, and with the part of each transition that does not
+/// emit a gamma ray, e.g. X-rays after conversion. Either kind of detection
+/// removes the event from every full energy peak of the cascade.
+pub struct Accompanying {
+    pub feeding: VectorF64,
+    pub non_gamma: MatrixF64,
+}
+
+/// The detection probabilities of the X-rays of `atomic` and the annihilation
+/// photons of `annihilation`. Each vacancy in a shell gives its X-ray lines
+/// with their intensities, and capture and conversion leave a vacancy in each
+/// shell with the given probabilities. Each positron gives two 511 keV
+/// photons, from β⁺ feeding or from pair formation, which takes the share
+/// α_π/α of the non-gamma part of its transition.
+pub fn make_accompanying(
+    atomic: &Atomic,
+    annihilation: &Annihilation,
+    x: &MatrixF64,
+    c: &MatrixF64,
+    peak_eff: &mut EfficiencyEvaluator,
+    total_eff: &mut TotalEvaluator,
+) -> Result<Accompanying, EfficiencyRangeError> {
+    let n_levels = x.size1();
+    // A vacancy gives at most one of the X-ray lines of its shell, and a capture or
+    // conversion happens in only one shell, so the detection probabilities of these
+    // alternatives add. Sampled intensities or K and L probabilities can push a sum
+    // above one, so each is capped at a certain detection.
+    let mut detected = [0.0; 2];
+    for xray in atomic.xrays.iter() {
+        let what = format!("{} X-ray", xray.shell);
+        let t = total_at(xray.energy, &what, peak_eff, total_eff)?;
+        detected[xray.shell as usize] += xray.intensity * t;
+    }
+    let detected = detected.map(|d: f64| d.min(1.0));
+    let per_vacancy = |k: f64, l: f64| {
+        (k * detected[Shell::K as usize] + l * detected[Shell::L as usize]).min(1.0)
+    };
+    let per_positron = if annihilation.is_empty() {
+        0.0
+    } else {
+        let t = match total_eff.annihilation {
+            Some(t) => {
+                let veto = total_eff.veto_at(ELECTRON_MASS).ok_or_else(|| {
+                    let what = "annihilation".to_string();
+                    range_error(total_eff.range(), total_eff.name(), what, ELECTRON_MASS)
+                })?;
+                t + veto
+            }
+            None => total_at(ELECTRON_MASS, "annihilation", peak_eff, total_eff)?,
+        };
+        1.0 - (1.0 - t).powi(2)
+    };
+
+    let mut feeding = VectorF64::new(n_levels).unwrap();
+    for c in atomic.captures.iter() {
+        feeding.set(
+            c.level,
+            feeding.get(c.level) + c.fraction * per_vacancy(c.k, c.l),
+        );
+    }
+    for p in annihilation.feedings.iter() {
+        feeding.set(p.level, feeding.get(p.level) + p.fraction * per_positron);
+    }
+    let mut non_gamma = make_square_matrix(n_levels, "non-gamma detection");
+    for c in atomic.conversions.iter() {
+        non_gamma.set(c.from, c.to, per_vacancy(c.k, c.l));
+    }
+    for p in annihilation.pairs.iter() {
+        let (x_ji, c_ji) = (x.get(p.from, p.to), c.get(p.from, p.to));
+        if x_ji <= c_ji {
+            continue;
+        }
+        // The non-gamma part is x - c = c α, of which c α_π forms pairs.
+        let share = (p.coefficient * c_ji / (x_ji - c_ji)).min(1.0);
+        let conversion = non_gamma.get(p.from, p.to);
+        non_gamma.set(
+            p.from,
+            p.to,
+            (1.0 - share) * conversion + share * per_positron,
+        );
+    }
+    Ok(Accompanying { feeding, non_gamma })
+}
+
+/// Probability that each level decays within the coincidence `window`, in
+/// seconds, 1 - exp(-λτ). Levels without a half-life decay at once.
+pub fn make_coincident_vector(levels: &[Level], window: f64) -> VectorF64 {
+    let mut p = VectorF64::new(levels.len()).unwrap();
+    for level in levels.iter() {
+        let value = match level.half_life {
+            Some((t, _)) if t > 0.0 => -f64::exp_m1(-std::f64::consts::LN_2 * window / t),
+            _ => 1.0,
+        };
+        p.set(level.idx, value);
+    }
+    p
+}
+
+/// Refinements of the instantaneous, gamma ray only cascade of Semkow.
+#[derive(Default)]
+pub struct Refinements {
+    pub accompanying: Option<Accompanying>,
+    /// Probability that each level decays within the coincidence window, so
+    /// that the photons before and after it can sum. If it does not, the
+    /// cascade starts afresh at that level.
+    pub coincident: Option<VectorF64>,
+    /// Angular correlation weights of consecutive gamma rays.
+    pub correlation: Option<Correlation>,
+}
+
+/// The correction matrix C_ji = S0_ji / S_ji, multiply an observed peak
+/// area by C_ji to get the summing free value.
 pub fn calculate_correction(
     x: &MatrixF64,
+    c: &MatrixF64,
     f: &VectorF64,
     peak_matrix: &MatrixF64,
     tot_matrix: &MatrixF64,
+    refinements: &Refinements,
 ) -> MatrixF64 {
+    let r = calculate_response(x, c, f, peak_matrix, tot_matrix, refinements);
+    let n_levels = f.len();
+    let mut correction = make_square_matrix(n_levels, "C");
+    for j in 0..n_levels {
+        for i in 0..n_levels {
+            correction.set(j, i, r.s0.get(j, i) / r.s.get(j, i));
+        }
+    }
+    correction
+}
+
+/// The full energy peak response per decay with summing, S, and without, S0.
+/// The peak of j -> i gets S_ji counts per decay for a feeding f normalized to
+/// one decay.
+pub struct Response {
+    pub s: MatrixF64,
+    pub s0: MatrixF64,
+    /// The part of S from cascades of several gamma rays that sum to the
+    /// energy of the peak, N_j (A - a)_ji M_i. The rest of S is what summing
+    /// out leaves of S0, N_j a_ji M_i.
+    pub summing_in: MatrixF64,
+    /// The diagonals of N and M.
+    pub n: VectorF64,
+    pub m: VectorF64,
+}
+
+impl Response {
+    /// The summing-out factor L = N_j a_ji M_i / S0_ji, the share of the peak
+    /// that no other detected photon takes out of it, and the summing-in
+    /// factor G = N_j (A - a)_ji M_i / S0_ji, so that S / S0 = L + G.
+    pub fn factors(&self, j: usize, i: usize) -> (f64, f64) {
+        let (s, s_in, s0) = (
+            self.s.get(j, i),
+            self.summing_in.get(j, i),
+            self.s0.get(j, i),
+        );
+        ((s - s_in) / s0, s_in / s0)
+    }
+}
+
+/// The response S and S0, see `Response`. Accompanying photons only take
+/// counts out of the peaks of S.
+#[allow(non_snake_case)]
+pub fn calculate_response(
+    x: &MatrixF64,
+    c: &MatrixF64,
+    f: &VectorF64,
+    peak_matrix: &MatrixF64,
+    tot_matrix: &MatrixF64,
+    refinements: &Refinements,
+) -> Response {
     let n_levels = f.len();
-    // right now there are no internal conversion corrections so c from 4a = x.
-    // All of the matrices from Eq.4 of Semkow.
+    // All of the matrices from Eq.4 of Semkow. Only the gamma branch, c, can
+    // be detected, but every transition (x) moves the cascade along.
     let mut a = make_square_matrix(n_levels, "a");
     let mut e = make_square_matrix(n_levels, "e");
     let mut b = make_square_matrix(n_levels, "b");
-    a.copy_from(x).unwrap();
+    a.copy_from(c).unwrap();
     a.mul_elements(peak_matrix).unwrap();
 
-    e.copy_from(x).unwrap();
+    e.copy_from(c).unwrap();
     e.mul_elements(tot_matrix).unwrap();
 
     b.copy_from(x).unwrap();
 
     b.sub(&e).unwrap();
 
-    // Now we need the matrices of Eq.5
+    // The part of x that is not a gamma ray, x - c, is also lost if its
+    // accompanying photon is detected.
+    if let Some(acc) = &refinements.accompanying {
+        let mut lost = make_square_matrix(n_levels, "lost");
+        lost.copy_from(x).unwrap();
+        lost.sub(c).unwrap();
+        lost.mul_elements(&acc.non_gamma).unwrap();
+        b.sub(&lost).unwrap();
+    }
+
+    // Now we do the no summing correction calculation. Eq.8
+    // A0 = a, M0 = I and without summing the populations only depend on x,
+    // so B0 = (I - x)^-1.
+    let mut N0 = VectorF64::new(n_levels).unwrap();
+    N0.copy_from(f).unwrap();
+    unit_lower_vector_solve(x, &mut N0, true);
+
+    // A level k that lives long enough only passes summing on with the
+    // probability p_k of decaying within the window. A chain through k gets a
+    // factor p_k, and with 1 - p_k whatever came before k no longer matters:
+    // N_k = p_k (f_k + sum_l N_l b_lk) + (1 - p_k) N0_k and
+    // M_k = p_k sum_i b_ki M_i + 1 - p_k.
+    let (a_chain, b_before, b_after) = match &refinements.coincident {
+        Some(p) => (
+            scaled(&a, p, false),
+            scaled(&b, p, false),
+            scaled(&b, p, true),
+        ),
+        None => (a.clone().unwrap(), b.clone().unwrap(), b.clone().unwrap()),
+    };
+
+    // The matrices of Eq.5 are power series in a and b. Both are strictly
+    // lower triangular, so A = sum_{k>=1} a^k = (I - a)^-1 a and
+    // B = sum_{k>=0} b^k = (I - b)^-1, which we get from triangular solves.
     let mut A = make_square_matrix(n_levels, "A");
-    let mut placeholder = make_square_matrix(n_levels, "temp for A");
     A.copy_from(&a).unwrap();
-    placeholder.copy_from(&a).unwrap();
-    for _i in 1..n_levels {
-        lower_triangular_multiply(&a, &mut placeholder);
-        A.add(&placeholder).unwrap();
+    unit_lower_solve(&a_chain, &mut A);
+
+    // N & M from Eq. 6 are diagonal, so we only keep the diagonals.
+    // N = diag(f^T B), so solve (I - b)^T n = f. Only feedings whose
+    // accompanying photons go undetected count.
+    let mut N = VectorF64::new(n_levels).unwrap();
+    N.copy_from(f).unwrap();
+    if let Some(acc) = &refinements.accompanying {
+        for j in 0..n_levels {
+            N.set(j, N.get(j) * (1.0 - acc.feeding.get(j)));
+        }
+    }
+    if let Some(p) = &refinements.coincident {
+        for j in 0..n_levels {
+            N.set(j, p.get(j) * N.get(j) + (1.0 - p.get(j)) * N0.get(j));
+        }
+    }
+    unit_lower_vector_solve(&b_before, &mut N, true);
+
+    // M = diag(B e_0), the first column of B.
+    let mut M = VectorF64::new(n_levels).unwrap();
+    if let Some(p) = &refinements.coincident {
+        for i in 1..n_levels {
+            M.set(i, 1.0 - p.get(i));
+        }
+    }
+    M.set(0, 1.0);
+    unit_lower_vector_solve(&b_after, &mut M, false);
+
+    // Now we calculate S, which is the sum correction and S0 which is
+    // the no sum corrected response. With diagonal N and M,
+    // S_ji = N_j A_ji M_i and S0_ji = N0_j a_ji.
+    let mut S = make_square_matrix(n_levels, "S");
+    let mut S0 = make_square_matrix(n_levels, "S0");
+    let mut S_in = make_square_matrix(n_levels, "S_in");
+    for j in 0..n_levels {
+        for i in 0..n_levels {
+            S.set(j, i, N.get(j) * A.get(j, i) * M.get(i));
+            S0.set(j, i, N0.get(j) * a.get(j, i));
+            S_in.set(j, i, N.get(j) * (A.get(j, i) - a.get(j, i)) * M.get(i));
+        }
     }
 
-    let mut E = make_square_matrix(n_levels, "E");
-    E.set_identity();
-    let mut B = make_square_matrix(n_levels, "B");
-    B.copy_from(&b).unwrap();
+    if let Some(w) = &refinements.correlation {
+        let p = |k: usize| refinements.coincident.as_ref().map_or(1.0, |p| p.get(k));
+        add_correlation(&mut S, &mut S_in, w, &a, &e, &N, &M, p);
+    }
 
-    let mut placeholder = make_square_matrix(n_levels, "temp for B");
-    placeholder.copy_from(&b).unwrap();
-    for _i in 1..n_levels {
-        lower_triangular_multiply(&b, &mut placeholder);
-        B.add(&placeholder).unwrap();
+    Response {
+        s: S,
+        s0: S0,
+        summing_in: S_in,
+        n: N,
+        m: M,
     }
+}
 
-    B.add(&E).unwrap();
+/// Add the angular correlations of consecutive gamma rays to S, to first order
+/// in W - 1. The peak of j -> i sums out with the gamma ray l -> j before it
+/// and i -> m after it, and sums in through j -> k -> i, which is added to
+/// S_in as well. Correlations between gamma rays further apart are neglected.
+#[allow(non_snake_case, clippy::too_many_arguments)]
+fn add_correlation(
+    S: &mut MatrixF64,
+    S_in: &mut MatrixF64,
+    w: &Correlation,
+    a: &MatrixF64,
+    e: &MatrixF64,
+    N: &VectorF64,
+    M: &VectorF64,
+    p: impl Fn(usize) -> f64,
+) {
+    let n_levels = N.len();
+    for j in 0..n_levels {
+        for i in 0..j {
+            // The sum peak of j -> k -> i exists without a transition j -> i.
+            let dA: f64 = (i + 1..j)
+                .map(|k| (w.weight(j, k, i) - 1.0) * a.get(j, k) * p(k) * a.get(k, i))
+                .sum();
+            let ds_in = N.get(j) * dA * M.get(i);
+            let a_ji = a.get(j, i);
+            let mut ds = ds_in;
+            if a_ji != 0.0 {
+                let dN: f64 = p(j)
+                    * (j + 1..n_levels)
+                        .map(|l| N.get(l) * (1.0 - w.weight(l, j, i)) * e.get(l, j))
+                        .sum::<f64>();
+                let dM: f64 = p(i)
+                    * (0..i)
+                        .map(|m| (1.0 - w.weight(j, i, m)) * e.get(i, m) * M.get(m))
+                        .sum::<f64>();
+                ds += dN * a_ji * M.get(i) + N.get(j) * a_ji * dM;
+            }
+            S.set(j, i, S.get(j, i) + ds);
+            S_in.set(j, i, S_in.get(j, i) + ds_in);
+        }
+    }
+}
 
-    // N & M from Eq. 6
-    let mut N = make_square_matrix(n_levels, "N");
-    let mut M = make_square_matrix(n_levels, "M");
+/// What takes counts out of a peak, or adds counts to it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cascade {
+    /// The gamma ray `from -> to` of the same cascade is detected as well.
+    Coincident(usize, usize),
+    /// An X-ray or annihilation photon of the same cascade is detected.
+    Accompanying,
+    /// Gamma rays through these levels sum to the energy of the peak.
+    Path(Vec<usize>),
+}
 
-    let mut placeholder = VectorF64::new(n_levels).unwrap();
-    placeholder.copy_from(f).unwrap();
-    lower_triangular_vector_multiply_reverse(&mut placeholder, &B);
-    for i in 0..n_levels {
-        N.set(i, i, placeholder.get(i));
+impl fmt::Display for Cascade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cascade::Coincident(from, to) => write!(f, "with {from} -> {to}"),
+            Cascade::Accompanying => write!(f, "with X-rays or annihilation photons"),
+            Cascade::Path(levels) => {
+                let levels: Vec<String> = levels.iter().map(|l| l.to_string()).collect();
+                write!(f, "{}", levels.join(" -> "))
+            }
+        }
     }
+}
 
-    // M is simple
-    for i in 0..n_levels {
-        M.set(i, i, B.get(i, 0));
+/// A cascade and the counts it takes out of a peak, or adds to it, per decay.
+/// Relative to S0 it is the cascade's part of the summing-out or summing-in
+/// factor.
+#[derive(Debug, Clone)]
+pub struct Contribution {
+    pub cascade: Cascade,
+    pub share: f64,
+}
+
+/// Keep the `top` largest contributions, largest first.
+pub(crate) fn keep_top(contributions: &mut Vec<Contribution>, top: usize) {
+    contributions.sort_by(|a, b| b.share.total_cmp(&a.share));
+    contributions.truncate(top);
+}
+
+/// The `top` paths of two or more gamma rays from `path` down to level `i`
+/// with the largest weights. The weight of a path is `weight` times its peak
+/// detection probabilities a, and p of each of its intermediate levels. Every
+/// a and p is at most one, so a path whose weight so far is not above the
+/// smallest one kept can not give a larger one.
+fn add_paths(
+    a: &MatrixF64,
+    p: &dyn Fn(usize) -> f64,
+    i: usize,
+    path: &mut Vec<usize>,
+    weight: f64,
+    top: usize,
+    found: &mut Vec<Contribution>,
+) {
+    let threshold = |found: &Vec<Contribution>| match found.len() < top {
+        true => 0.0,
+        false => found.last().map_or(0.0, |c| c.share),
+    };
+    let k = *path.last().expect("a path starts at a level");
+    if path.len() >= 2 && weight * a.get(k, i) > threshold(found) {
+        let mut levels = path.clone();
+        levels.push(i);
+        found.push(Contribution {
+            cascade: Cascade::Path(levels),
+            share: weight * a.get(k, i),
+        });
+        keep_top(found, top);
     }
+    for l in (i + 1..k).rev() {
+        let w = weight * a.get(k, l) * p(l);
+        if w > threshold(found) {
+            path.push(l);
+            add_paths(a, p, i, path, w, top, found);
+            path.pop();
+        }
+    }
+}
 
-    // Now we do the no summing correction calculation. Eq.8
-    let mut A0 = make_square_matrix(n_levels, "A0");
-    let mut B0 = make_square_matrix(n_levels, "B0");
-    let mut M0 = make_square_matrix(n_levels, "M0");
-    let mut N0 = make_square_matrix(n_levels, "N0");
+/// Emission probability per decay of each gamma ray, I_ji = N0_j c_ji, where
+/// N0 = (I - x)^-T f is the population of each level without summing.
+pub fn make_emission_matrix(x: &MatrixF64, c: &MatrixF64, f: &VectorF64) -> MatrixF64 {
+    let n_levels = f.len();
+    let mut n0 = VectorF64::new(n_levels).unwrap();
+    n0.copy_from(f).unwrap();
+    unit_lower_vector_solve(x, &mut n0, true);
+    let mut emission = make_square_matrix(n_levels, "I");
+    for j in 0..n_levels {
+        for i in 0..n_levels {
+            emission.set(j, i, n0.get(j) * c.get(j, i));
+        }
+    }
+    emission
+}
 
-    A0.copy_from(&a).unwrap();
-    M0.copy_from(&E).unwrap();
+/// The matrices of a level scheme that do not depend on the efficiencies.
+pub(crate) struct SchemeMatrices {
+    pub x: MatrixF64,
+    pub c: MatrixF64,
+    pub f: VectorF64,
+    pub energies: MatrixF64,
+    pub atomic: Atomic,
+    pub annihilation: Annihilation,
+    pub coincident: Option<VectorF64>,
+    pub correlation: Option<Correlation>,
+}
 
-    // B0 calculation
-    let mut placeholder = make_square_matrix(n_levels, "temp for B0");
-    B0.copy_from(x).unwrap();
-    placeholder.copy_from(&a).unwrap();
-    for _i in 1..n_levels {
-        lower_triangular_multiply(&a, &mut placeholder);
-        B0.add(&placeholder).unwrap();
+impl SchemeMatrices {
+    /// Without a coincidence `window` every level decays at once, and without
+    /// an `attenuation` gamma rays are emitted independently.
+    pub fn new(
+        scheme: &LevelScheme,
+        window: Option<f64>,
+        attenuation: Option<Attenuation>,
+    ) -> Self {
+        let (x, f) = make_x_and_f_matrix(&scheme.branches, &scheme.levels);
+        let c = make_c_matrix(&x, &scheme.branches);
+        let energies = make_transition_energies(&scheme.branches, &scheme.levels);
+        Self {
+            x,
+            c,
+            f,
+            energies,
+            atomic: scheme.atomic.clone(),
+            annihilation: scheme.annihilation.clone(),
+            coincident: window.map(|w| make_coincident_vector(&scheme.levels, w)),
+            correlation: attenuation.and_then(|q| Correlation::new(scheme, q)),
+        }
     }
 
-    B0.add(&E).unwrap();
+    fn refinements(
+        &self,
+        peak_eff: &mut EfficiencyEvaluator,
+        total_eff: &mut TotalEvaluator,
+    ) -> Result<Refinements, EfficiencyRangeError> {
+        let accompanying = if self.atomic.xrays.is_empty() && self.annihilation.is_empty() {
+            None
+        } else {
+            Some(make_accompanying(
+                &self.atomic,
+                &self.annihilation,
+                &self.x,
+                &self.c,
+                peak_eff,
+                total_eff,
+            )?)
+        };
+        Ok(Refinements {
+            accompanying,
+            coincident: self.coincident.as_ref().map(|p| p.clone().unwrap()),
+            correlation: self.correlation.clone(),
+        })
+    }
 
-    // N0 calculation
-    let mut placeholder = VectorF64::new(n_levels).unwrap();
-    placeholder.copy_from(f).unwrap();
-    lower_triangular_vector_multiply_reverse(&mut placeholder, &B0);
-    for i in 0..n_levels {
-        N0.set(i, i, placeholder.get(i));
+    pub fn correction(
+        &self,
+        peak_eff: &mut EfficiencyEvaluator,
+        total_eff: &mut TotalEvaluator,
+    ) -> Result<MatrixF64, EfficiencyRangeError> {
+        let (peak_matrix, total_matrix) = make_eff_matrix(&self.energies, peak_eff, total_eff)?;
+        let refinements = self.refinements(peak_eff, total_eff)?;
+        Ok(calculate_correction(
+            &self.x,
+            &self.c,
+            &self.f,
+            &peak_matrix,
+            &total_matrix,
+            &refinements,
+        ))
     }
 
-    // Now we calculate S, which is the sum correction and S0 which is
-    // the no sum corrected response. These can then be divided for the correction
-    // matrix
-    let mut S = make_square_matrix(n_levels, "S");
-    let mut S0 = make_square_matrix(n_levels, "S0");
-    let mut placeholder = make_square_matrix(n_levels, "temp for S and S0");
+    /// S and S0, see `calculate_response`.
+    pub fn response(
+        &self,
+        peak_eff: &mut EfficiencyEvaluator,
+        total_eff: &mut TotalEvaluator,
+    ) -> Result<Response, EfficiencyRangeError> {
+        let (peak_matrix, total_matrix) = make_eff_matrix(&self.energies, peak_eff, total_eff)?;
+        let refinements = self.refinements(peak_eff, total_eff)?;
+        Ok(calculate_response(
+            &self.x,
+            &self.c,
+            &self.f,
+            &peak_matrix,
+            &total_matrix,
+            &refinements,
+        ))
+    }
 
-    matrix_multiply(&N, &A, &mut placeholder);
-    matrix_multiply(&placeholder, &M, &mut S);
+    /// The summing-out of every peak by each cascade: for every gamma ray, and
+    /// for the accompanying photons if there are any, how much of S - S_in
+    /// comes back when it is never detected.
+    pub fn losses(
+        &self,
+        peak_eff: &mut EfficiencyEvaluator,
+        total_eff: &mut TotalEvaluator,
+    ) -> Result<Vec<(Cascade, MatrixF64)>, EfficiencyRangeError> {
+        let (peak_matrix, total_matrix) = make_eff_matrix(&self.energies, peak_eff, total_eff)?;
+        let refinements = self.refinements(peak_eff, total_eff)?;
+        let response = |total: &MatrixF64, refinements: &Refinements| {
+            let r = calculate_response(&self.x, &self.c, &self.f, &peak_matrix, total, refinements);
+            let mut out = r.s;
+            out.sub(&r.summing_in).unwrap();
+            out
+        };
+        let nominal = response(&total_matrix, &refinements);
+        let difference = |mut out: MatrixF64| {
+            out.sub(&nominal).unwrap();
+            out
+        };
+
+        let n_levels = self.f.len();
+        let mut losses = Vec::new();
+        for u in 0..n_levels {
+            for v in 0..u {
+                if self.c.get(u, v) <= 0.0 {
+                    continue;
+                }
+                let mut total = total_matrix.clone().unwrap();
+                total.set(u, v, 0.0);
+                losses.push((
+                    Cascade::Coincident(u, v),
+                    difference(response(&total, &refinements)),
+                ));
+            }
+        }
+        if refinements.accompanying.is_some() {
+            let without = Refinements {
+                accompanying: None,
+                ..refinements
+            };
+            losses.push((
+                Cascade::Accompanying,
+                difference(response(&total_matrix, &without)),
+            ));
+        }
+        Ok(losses)
+    }
+
+    /// The `top` paths that add the most counts to the peak of j -> i, N_j
+    /// times the a and p along the path times M_i. The angular correlations
+    /// are left out.
+    pub fn gains(
+        &self,
+        peak_eff: &mut EfficiencyEvaluator,
+        total_eff: &mut TotalEvaluator,
+        j: usize,
+        i: usize,
+        top: usize,
+    ) -> Result<Vec<Contribution>, EfficiencyRangeError> {
+        let (peak_matrix, total_matrix) = make_eff_matrix(&self.energies, peak_eff, total_eff)?;
+        let refinements = self.refinements(peak_eff, total_eff)?;
+        let r = calculate_response(
+            &self.x,
+            &self.c,
+            &self.f,
+            &peak_matrix,
+            &total_matrix,
+            &refinements,
+        );
+        let mut a = self.c.clone().unwrap();
+        a.mul_elements(&peak_matrix).unwrap();
+        let p = |k: usize| self.coincident.as_ref().map_or(1.0, |p| p.get(k));
+        let mut found = Vec::new();
+        if top > 0 {
+            let weight = r.n.get(j) * r.m.get(i);
+            add_paths(&a, &p, i, &mut vec![j], weight, top, &mut found);
+        }
+        Ok(found)
+    }
+
+    /// Emission probability of each gamma ray per decay.
+    pub fn emission(&self) -> MatrixF64 {
+        make_emission_matrix(&self.x, &self.c, &self.f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::level_info::Multipolarity;
+
+    #[allow(non_snake_case)]
+    fn lower_triangular_multiply(A: &MatrixF64, B: &mut MatrixF64) {
+        blas::level3::dtrmm(
+            rgsl::CblasSide::Left,
+            rgsl::CblasUplo::Lower,
+            rgsl::CblasTranspose::NoTranspose,
+            rgsl::CblasDiag::NonUnit,
+            1.0,
+            A,
+            B,
+        )
+        .unwrap();
+    }
+
+    /// sum_{k=first}^{n-1} t^k by repeated multiplication.
+    fn power_series(t: &MatrixF64, first: usize) -> MatrixF64 {
+        let n = t.size1();
+        let mut sum = make_square_matrix(n, "sum");
+        let mut term = make_square_matrix(n, "term");
+        term.set_identity();
+        for k in 0..n {
+            if k >= first {
+                sum.add(&term).unwrap();
+            }
+            lower_triangular_multiply(t, &mut term);
+        }
+        sum
+    }
+
+    /// The n-term power series evaluation of Semkow's Eq.5-8 that the
+    /// triangular solves replace.
+    fn series_correction(
+        x: &MatrixF64,
+        c: &MatrixF64,
+        f: &VectorF64,
+        peak_matrix: &MatrixF64,
+        tot_matrix: &MatrixF64,
+    ) -> MatrixF64 {
+        let n = f.len();
+        let mut a = make_square_matrix(n, "a");
+        a.copy_from(c).unwrap();
+        a.mul_elements(peak_matrix).unwrap();
+        let mut b = make_square_matrix(n, "b");
+        b.copy_from(c).unwrap();
+        b.mul_elements(tot_matrix).unwrap();
+        b.scale(-1.0).unwrap();
+        b.add(x).unwrap();
 
-    placeholder.set_zero();
-    matrix_multiply(&N0, &A0, &mut placeholder);
-    matrix_multiply(&placeholder, &M0, &mut S0);
+        let big_a = power_series(&a, 1);
+        let big_b = power_series(&b, 0);
+        let big_b0 = power_series(x, 0);
 
-    // Divide elements in place
-    S0.div_elements(&S).unwrap();
+        let mut correction = make_square_matrix(n, "C");
+        for j in 0..n {
+            let n_j: f64 = (0..n).map(|k| f.get(k) * big_b.get(k, j)).sum();
+            let n0_j: f64 = (0..n).map(|k| f.get(k) * big_b0.get(k, j)).sum();
+            for i in 0..n {
+                let s = n_j * big_a.get(j, i) * big_b.get(i, 0);
+                let s0 = n0_j * a.get(j, i);
+                correction.set(j, i, s0 / s);
+            }
+        }
+        correction
+    }
+
+    #[test]
+    fn triangular_solve_matches_power_series() {
+        let levels: Vec<Level> = [0.0, 440.2, 2076.2, 2390.9, 3677.9, 5766.0]
+            .iter()
+            .enumerate()
+            .map(|(idx, &energy)| {
+                Level::new(idx, energy, 0.0, [0.0, 0.0, 0.1, 0.0, 0.3, 0.6][idx], 0.0)
+            })
+            .collect();
+        let branches = vec![
+            Branch::new(1, 0, 1.0, 0.0, 0.0, 0.0),
+            Branch::new(2, 0, 0.1, 0.0, 0.0, 0.0),
+            Branch::new(2, 1, 0.9, 0.0, 0.2, 0.0),
+            Branch::new(3, 0, 0.6, 0.0, 0.0, 0.0),
+            Branch::new(3, 1, 0.4, 0.0, 0.0, 0.0),
+            Branch::new(4, 1, 0.7, 0.0, 0.0, 0.0),
+            Branch::new(4, 2, 0.2, 0.0, 0.0, 0.0),
+            Branch::new(4, 3, 0.1, 0.0, 1.5, 0.0),
+            Branch::new(5, 0, 0.3, 0.0, 0.0, 0.0),
+            Branch::new(5, 2, 0.3, 0.0, 0.0, 0.0),
+            Branch::new(5, 3, 0.2, 0.0, 0.0, 0.0),
+            Branch::new(5, 4, 0.2, 0.0, 0.05, 0.0),
+        ];
+        let (x, f) = make_x_and_f_matrix(&branches, &levels);
+        let c = make_c_matrix(&x, &branches);
+        let energies = make_transition_energies(&branches, &levels);
+        let n = levels.len();
+        let mut peak = make_square_matrix(n, "peak");
+        let mut total = make_square_matrix(n, "total");
+        for j in 0..n {
+            for i in 0..n {
+                let e = energies.get(j, i);
+                if e > 0.0 {
+                    peak.set(j, i, 30.0 / e);
+                    total.set(j, i, 0.2 + 10.0 / e);
+                }
+            }
+        }
+
+        let expected = series_correction(&x, &c, &f, &peak, &total);
+        let result = calculate_correction(&x, &c, &f, &peak, &total, &Refinements::default());
+        for branch in branches.iter() {
+            let (j, i) = (branch.from, branch.to);
+            let (want, got) = (expected.get(j, i), result.get(j, i));
+            assert!(
+                (want - got).abs() < 1e-12 * want.abs(),
+                "C[{j}][{i}]: series {want}, triangular solve {got}"
+            );
+        }
+    }
+
+    #[test]
+    fn converted_cascade_by_hand() {
+        // 2 -> 1 -> 0 with a crossover 2 -> 0, and conversion of 2 -> 1 only.
+        let levels: Vec<Level> = [(0.0, 0.0), (1000.0, 0.0), (1500.0, 1.0)]
+            .iter()
+            .enumerate()
+            .map(|(idx, &(energy, feeding))| Level::new(idx, energy, 0.0, feeding, 0.0))
+            .collect();
+        let alpha = 0.5;
+        let branches = vec![
+            Branch::new(1, 0, 1.0, 0.0, 0.0, 0.0),
+            Branch::new(2, 1, 0.8, 0.0, alpha, 0.0),
+            Branch::new(2, 0, 0.2, 0.0, 0.0, 0.0),
+        ];
+        let (x, f) = make_x_and_f_matrix(&branches, &levels);
+        let c = make_c_matrix(&x, &branches);
+        let mut peak = make_square_matrix(3, "peak");
+        let mut total = make_square_matrix(3, "total");
+        for (j, i, p, t) in [(1, 0, 0.04, 0.18), (2, 1, 0.05, 0.2), (2, 0, 0.03, 0.15)] {
+            peak.set(j, i, p);
+            total.set(j, i, t);
+        }
+        let r = calculate_response(&x, &c, &f, &peak, &total, &Refinements::default());
+
+        let c21 = 0.8 / (1.0 + alpha);
+        // 2 -> 1: nothing may be detected after it.
+        let s21 = c21 * 0.05 * (1.0 - 0.18);
+        let s0_21 = c21 * 0.05;
+        // 1 -> 0: level 1 is reached by every 2 -> 1 transition, gamma ray or
+        // conversion, but only the undetected gamma rays leave the peak intact.
+        let s10 = (0.8 - c21 * 0.2) * 0.04;
+        let s0_10 = 0.8 * 0.04;
+        // 2 -> 0: the crossover plus the summed 2 -> 1 -> 0 gamma rays.
+        let s20 = 0.2 * 0.03 + c21 * 0.05 * 0.04;
+        let s0_20 = 0.2 * 0.03;
+        for (j, i, s, s0) in [(2, 1, s21, s0_21), (1, 0, s10, s0_10), (2, 0, s20, s0_20)] {
+            assert!(
+                (r.s.get(j, i) - s).abs() < 1e-15,
+                "S[{j}][{i}] = {}",
+                r.s.get(j, i)
+            );
+            assert!(
+                (r.s0.get(j, i) - s0).abs() < 1e-15,
+                "S0[{j}][{i}] = {}",
+                r.s0.get(j, i)
+            );
+        }
+        let correction = calculate_correction(&x, &c, &f, &peak, &total, &Refinements::default());
+        assert!((correction.get(2, 1) - 1.0 / (1.0 - 0.18)).abs() < 1e-12);
+        assert!((correction.get(1, 0) - 0.8 / (0.8 - c21 * 0.2)).abs() < 1e-12);
+        assert!((correction.get(2, 0) - s0_20 / s20).abs() < 1e-12);
+    }
+
+    #[test]
+    fn correlated_cascade_by_hand() {
+        // 4 -> 2 -> 0 spins with two pure E2 gamma rays and no crossover.
+        let levels: Vec<Level> = [(0.0, 0.0, 0.0), (1332.5, 0.0, 2.0), (2505.7, 1.0, 4.0)]
+            .iter()
+            .enumerate()
+            .map(|(idx, &(energy, feeding, spin))| Level {
+                spin: Some(spin),
+                ..Level::new(idx, energy, 0.0, feeding, 0.0)
+            })
+            .collect();
+        let e2 = Multipolarity {
+            order: 2,
+            mixing: 0.0,
+            dmixing: 0.0,
+        };
+        let branches: Vec<Branch> = [(2, 1), (1, 0)]
+            .iter()
+            .map(|&(from, to)| Branch {
+                multipolarity: Some(e2),
+                ..Branch::new(from, to, 1.0, 0.0, 0.0, 0.0)
+            })
+            .collect();
+        let scheme = LevelScheme::new(levels.clone(), branches.clone());
+        let attenuation = Attenuation { q2: 0.9, q4: 0.7 };
+        let w = 1.0 + 5.0 / 49.0 * 0.9f64.powi(2) + 4.0 / 441.0 * 0.7f64.powi(2);
+
+        let (x, f) = make_x_and_f_matrix(&branches, &levels);
+        let c = make_c_matrix(&x, &branches);
+        let mut peak = make_square_matrix(3, "peak");
+        let mut total = make_square_matrix(3, "total");
+        let (p10, t10, p21, t21) = (0.04, 0.18, 0.05, 0.2);
+        peak.set(1, 0, p10);
+        total.set(1, 0, t10);
+        peak.set(2, 1, p21);
+        total.set(2, 1, t21);
+        let plain = calculate_response(&x, &c, &f, &peak, &total, &Refinements::default());
+        let refinements = Refinements {
+            correlation: Some(Correlation::new(&scheme, attenuation).unwrap()),
+            ..Default::default()
+        };
+        let r = calculate_response(&x, &c, &f, &peak, &total, &refinements);
 
-    S0
+        // N_2 = M_0 = 1 and every level decays within the window, p = 1.
+        // The sum peak 2 -> 0 changes by (W - 1) N_2 a_21 p_1 a_10 M_0, and
+        // the gamma rays sum out of each other's peak by a factor W.
+        let expected = [
+            (2, 0, (w - 1.0) * p21 * p10),
+            (2, 1, (1.0 - w) * p21 * t10),
+            (1, 0, (1.0 - w) * t21 * p10),
+        ];
+        for (j, i, ds) in expected {
+            let got = r.s.get(j, i) - plain.s.get(j, i);
+            assert!(
+                (got - ds).abs() < 1e-15,
+                "dS[{j}][{i}] = {got}, expected {ds}"
+            );
+        }
+        let ds_in = r.summing_in.get(2, 0) - plain.summing_in.get(2, 0);
+        assert!((ds_in - (w - 1.0) * p21 * p10).abs() < 1e-15);
+    }
 }